@@ -0,0 +1,308 @@
+//! Scale benchmarks for `Database`, driven off-canister.
+//!
+//! `mixed_load_keeps_indexes_consistent` runs with `cargo test` and checks the sort indexes
+//! are consistent after a mixed load, without reporting timings. The timed benchmarks are
+//! ignored by default, run them with:
+//!
+//! ```bash
+//! cargo test --release bench_ -- --ignored --nocapture
+//! ```
+
+use crate::clock::MockClock;
+use crate::db::Database;
+use crate::types::*;
+use candid::{Nat, Principal};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+const TRAITS: [(&str, [&str; 5]); 3] = [
+    ("base", ["Bronze", "Silver", "Gold", "Platinum", "Diamond"]),
+    (
+        "biggem",
+        ["Diamond", "Psychedelic", "Emerald", "Sapphire", "Ruby"],
+    ),
+    ("rim", ["Bronze", "Silver", "Gold", "Platinum", "Diamond"]),
+];
+
+const SORT_KEYS: [&str; 7] = [
+    "listing_price",
+    "offer_price",
+    "sale_price",
+    "last_listing",
+    "last_offer",
+    "last_sale",
+    "all",
+];
+
+/// xorshift, to generate the same load on every run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// total time and count of each timed operation
+#[derive(Default)]
+struct Timings(BTreeMap<&'static str, (u32, Duration)>);
+
+impl Timings {
+    fn time<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        let entry = self.0.entry(name).or_default();
+        entry.0 += 1;
+        entry.1 += start.elapsed();
+        result
+    }
+
+    fn report(&self, title: &str) {
+        println!("\n{}", title);
+        println!(
+            "{:<16} {:>8} {:>12} {:>12}",
+            "operation", "count", "total ms", "avg us"
+        );
+        for (name, (count, total)) in self.0.iter() {
+            println!(
+                "{:<16} {:>8} {:>12.2} {:>12.2}",
+                name,
+                count,
+                total.as_secs_f64() * 1e3,
+                total.as_secs_f64() * 1e6 / *count as f64
+            );
+        }
+    }
+}
+
+fn event(token_id: u64, operation: Operation) -> Event {
+    Event {
+        nft_canister_id: Principal::management_canister(),
+        fungible_id: None,
+        token_id: token_id.to_string(),
+        operation,
+        seller: None,
+        event_id: None,
+        time: None,
+    }
+}
+
+fn buyer(rng: &mut Rng) -> Principal {
+    Principal::from_slice(&[rng.below(32) as u8 + 1])
+}
+
+fn operation_name(operation: &Operation) -> &'static str {
+    match operation {
+        Operation::Mint { .. } => "mint",
+        Operation::Transfer { .. } => "transfer",
+        Operation::Burn => "burn",
+        Operation::UpdateMetadata { .. } => "updateMetadata",
+        Operation::MakeListing { .. } => "makeListing",
+        Operation::CancelListing => "cancelListing",
+        Operation::MakeOffer { .. } => "makeOffer",
+        Operation::CancelOffer { .. } => "cancelOffer",
+        Operation::DirectBuy { .. } => "directBuy",
+        Operation::AcceptOffer { .. } => "acceptOffer",
+    }
+}
+
+fn random_operation(rng: &mut Rng) -> Operation {
+    let price = Nat::from(rng.below(1_000) + 1);
+    match rng.below(10) {
+        0..=2 => Operation::MakeListing { price },
+        3 => Operation::CancelListing,
+        4..=5 => Operation::MakeOffer {
+            buyer: buyer(rng),
+            price,
+        },
+        6 => Operation::CancelOffer { buyer: buyer(rng) },
+        7..=8 => Operation::DirectBuy {
+            buyer: buyer(rng),
+            price,
+        },
+        _ => Operation::AcceptOffer {
+            buyer: buyer(rng),
+            price,
+        },
+    }
+}
+
+/// value a token is expected to be ordered by in a sort index
+fn sort_value(key: &str, token: &TokenData) -> Option<Nat> {
+    match key {
+        "listing_price" => token.price.clone(),
+        "offer_price" => token.best_offer.clone(),
+        "sale_price" => token.last_sale.as_ref().map(|sale| sale.price.clone()),
+        "last_listing" => token.last_listing.clone(),
+        "last_offer" => token.last_offer.clone(),
+        "last_sale" => token.last_sale.as_ref().map(|sale| sale.time.clone()),
+        _ => None,
+    }
+}
+
+/// page through every sort index, and check it is ordered and complete
+fn check_indexes(db: &Database, tokens: u64, timings: &mut Timings) {
+    for key in SORT_KEYS {
+        let mut request = QueryRequest {
+            sort_key: key.to_string(),
+            last_index: None,
+            cursor: None,
+            count: Some(PAGE_SIZE_LIMIT),
+            traits: None,
+            filter: None,
+            ranges: None,
+            reverse: None,
+            fungible: None,
+        };
+
+        let mut seen = 0;
+        let mut previous: Option<Nat> = None;
+        loop {
+            let response = timings.time("query page", || db.query(request.clone()));
+            assert!(response.error.is_none(), "{}: {:?}", key, response.error);
+
+            for token in response.data.iter() {
+                if let Some(value) = sort_value(key, token) {
+                    if let Some(previous) = &previous {
+                        assert!(value <= *previous, "{} is not in descending order", key);
+                    }
+                    previous = Some(value);
+                }
+            }
+            seen += response.data.len();
+
+            match response.cursor {
+                Some(cursor) => request.cursor = Some(cursor),
+                None => {
+                    assert_eq!(seen, response.total, "{} did not return every token", key);
+                    break;
+                }
+            }
+        }
+
+        if key == "all" {
+            assert_eq!(seen as u64, tokens);
+        }
+    }
+}
+
+/// time filtered queries and facets
+fn bench_queries(db: &Database, rng: &mut Rng, timings: &mut Timings) {
+    for _ in 0..100 {
+        let (key, values) = TRAITS[rng.below(TRAITS.len() as u64) as usize];
+        let value = values[rng.below(values.len() as u64) as usize];
+        let sort_key = SORT_KEYS[rng.below(SORT_KEYS.len() as u64) as usize];
+
+        let request = QueryRequest {
+            sort_key: sort_key.to_string(),
+            last_index: None,
+            cursor: None,
+            count: None,
+            traits: Some(vec![(
+                key.to_string(),
+                GenericValue::TextContent(value.to_string()),
+            )]),
+            filter: None,
+            ranges: None,
+            reverse: None,
+            fungible: None,
+        };
+        timings.time("query traits", || db.query(request.clone()));
+
+        let request = QueryRequest {
+            filter: Some(TraitFilter::Not(Box::new(TraitFilter::Trait(
+                key.to_string(),
+                GenericValue::TextContent(value.to_string()),
+            )))),
+            ranges: Some(vec![RangeFilter {
+                sort_key: "listing_price".to_string(),
+                min: Some(Nat::from(100)),
+                max: Some(Nat::from(500)),
+            }]),
+            ..request
+        };
+        timings.time("query ranges", || db.query(request.clone()));
+
+        let request = FacetRequest {
+            sort_key: Some("listing_price".to_string()),
+            traits: None,
+            filter: None,
+            ranges: None,
+        };
+        timings.time("facets", || db.facets(request));
+    }
+}
+
+/// index a mixed load and run queries against it, returning the timings
+fn run(tokens: u64, actions: u64) -> Timings {
+    let mut db = Database::new();
+    let clock = MockClock::new(0);
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut timings = Timings::default();
+
+    for id in 0..tokens {
+        let mut traits: HashMap<String, GenericValue> = HashMap::new();
+        for (key, values) in TRAITS {
+            let value = values[rng.below(values.len() as u64) as usize];
+            traits.insert(
+                key.to_string(),
+                GenericValue::TextContent(value.to_string()),
+            );
+        }
+        traits.insert(
+            "level".to_string(),
+            GenericValue::Nat8Content(rng.below(100) as u8),
+        );
+
+        let event = event(
+            id,
+            Operation::Mint {
+                traits: Some(traits),
+                to: None,
+            },
+        );
+        clock.advance(1_000_000_000);
+        timings
+            .time("mint", || db.index_event(event, &clock))
+            .unwrap();
+    }
+
+    for _ in 0..actions {
+        let operation = random_operation(&mut rng);
+        let name = operation_name(&operation);
+        let event = event(rng.below(tokens), operation);
+        clock.advance(1_000_000_000);
+        timings
+            .time(name, || db.index_event(event, &clock))
+            .unwrap();
+    }
+
+    check_indexes(&db, tokens, &mut timings);
+    bench_queries(&db, &mut rng, &mut timings);
+
+    timings
+}
+
+#[test]
+fn mixed_load_keeps_indexes_consistent() {
+    run(200, 1_000);
+}
+
+#[test]
+#[ignore]
+fn bench_small() {
+    run(1_000, 5_000).report("1000 tokens, 5000 actions");
+}
+
+#[test]
+#[ignore]
+fn bench_scale() {
+    run(10_000, 100_000).report("10000 tokens, 100000 actions");
+}
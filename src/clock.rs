@@ -0,0 +1,38 @@
+/// Source of the current time, in nanoseconds since the epoch.
+///
+/// Indexing takes a clock instead of reading the replica time directly, so the database can
+/// be driven outside of a canister.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// Time of the IC replica.
+pub struct IcClock;
+
+impl Clock for IcClock {
+    fn now(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+}
+
+/// Deterministic clock for tests, only moving when set or advanced.
+#[cfg(test)]
+pub struct MockClock(std::cell::Cell<u64>);
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(time: u64) -> Self {
+        MockClock(std::cell::Cell::new(time))
+    }
+
+    pub fn advance(&self, nanos: u64) {
+        self.0.set(self.0.get() + nanos);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.0.get()
+    }
+}
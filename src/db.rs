@@ -1,16 +1,46 @@
+use crate::clock::Clock;
+use crate::index::SortIndex;
 use crate::types::*;
-use candid::{CandidType, Nat, Principal};
-use ic_cdk::api::time;
-use std::collections::{HashMap, HashSet};
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Bound;
 
-#[derive(CandidType, Clone)]
+#[derive(CandidType, Clone, Deserialize)]
 pub struct Database {
     // pre-sorted indexes
-    sort_index: TokenIndex,
+    sort_index: HashMap<String, SortIndex>,
+    // fungible: price sort indexes of the prices in the fungible
+    fungible_index: HashMap<Principal, HashMap<String, SortIndex>>,
+    // fungible: exchange rate to the common unit of the normalized price indexes
+    rates: HashMap<Principal, FungibleRate>,
     // filter key: generic value: index
     trait_maps: HashMap<String, GenericIndex>,
+    // filter key: numeric value: index, ordered for range filters
+    numeric_traits: HashMap<String, BTreeMap<Int, Vec<String>>>,
     // token id: token data
     db: HashMap<String, TokenData>,
+    // append only log of applied events, the index of an entry is its sequence number
+    events: Vec<LoggedEvent>,
+    // source event ids of applied events
+    applied_ids: HashSet<u64>,
+    // token id: most recent listings, offers and sales, in ascending time order
+    history: HashMap<String, Vec<HistoryEntry>>,
+    // owner: owned token ids
+    owned_by: HashMap<Principal, BTreeSet<String>>,
+    // buyer: token ids with an open offer from the buyer
+    offers_by: HashMap<Principal, BTreeSet<String>>,
+    // seller: listed token ids
+    listings_by: HashMap<Principal, BTreeSet<String>>,
+    // buyer: purchases, in ascending time order
+    purchases: HashMap<Principal, Vec<AccountSale>>,
+    // seller: sales, in ascending time order
+    sales: HashMap<Principal, Vec<AccountSale>>,
+    // start time: hourly candle
+    candles: HashMap<Principal, BTreeMap<u64, Candle>>,
+    // all sales
+    volume: HashMap<Principal, Volume>,
+    // time of the most recent applied event
+    last_time: u64,
 }
 
 impl Database {
@@ -18,16 +48,33 @@ impl Database {
         Database {
             // define sort indexes to unwrap get_mut safely
             sort_index: HashMap::from([
-                ("listing_price".to_string(), vec![]),
-                ("offer_price".to_string(), vec![]),
-                ("sale_price".to_string(), vec![]),
-                ("last_listing".to_string(), vec![]),
-                ("last_offer".to_string(), vec![]),
-                ("last_sale".to_string(), vec![]),
-                ("all".to_string(), vec![]),
+                ("listing_price".to_string(), SortIndex::default()),
+                ("offer_price".to_string(), SortIndex::default()),
+                ("sale_price".to_string(), SortIndex::default()),
+                ("last_listing".to_string(), SortIndex::default()),
+                ("last_offer".to_string(), SortIndex::default()),
+                ("last_sale".to_string(), SortIndex::default()),
+                ("all".to_string(), SortIndex::default()),
+                ("normalized_listing_price".to_string(), SortIndex::default()),
+                ("normalized_offer_price".to_string(), SortIndex::default()),
+                ("normalized_sale_price".to_string(), SortIndex::default()),
             ]),
+            fungible_index: HashMap::new(),
+            rates: HashMap::new(),
             trait_maps: HashMap::new(),
+            numeric_traits: HashMap::new(),
             db: HashMap::new(),
+            events: vec![],
+            applied_ids: HashSet::new(),
+            history: HashMap::new(),
+            owned_by: HashMap::new(),
+            offers_by: HashMap::new(),
+            listings_by: HashMap::new(),
+            purchases: HashMap::new(),
+            sales: HashMap::new(),
+            candles: HashMap::new(),
+            volume: HashMap::new(),
+            last_time: 0,
         }
     }
 
@@ -35,43 +82,644 @@ impl Database {
         self.db.get(token_id)
     }
 
+    /// look up tokens by id, in the order of the ids
+    pub fn get_tokens(
+        &self,
+        token_ids: Vec<String>,
+    ) -> Result<Vec<Result<TokenData, &'static str>>, &'static str> {
+        if token_ids.len() > PAGE_SIZE_LIMIT {
+            return Err("Too many token ids");
+        }
+
+        Ok(token_ids
+            .iter()
+            .map(|id| self.get(id).cloned().ok_or("Token not indexed"))
+            .collect())
+    }
+
+    /// get a page of the event log, in ascending sequence order
+    pub fn get_events(&self, start: Option<u64>, count: Option<usize>) -> EventsResponse {
+        let mut size = count.unwrap_or(DEFAULT_PAGE_SIZE);
+        if size > PAGE_SIZE_LIMIT {
+            size = PAGE_SIZE_LIMIT;
+        }
+
+        let total = self.events.len() as u64;
+        let start = start.unwrap_or_default().min(total) as usize;
+        let end = (start + size).min(self.events.len());
+
+        EventsResponse {
+            total,
+            next: if (end as u64) < total {
+                Some(end as u64)
+            } else {
+                None
+            },
+            data: self.events[start..end].to_vec(),
+        }
+    }
+
+    /// get a page of a token's history, newest first
+    pub fn get_token_history(
+        &self,
+        token_id: &String,
+        start: Option<usize>,
+        count: Option<usize>,
+    ) -> HistoryResponse {
+        let history = match self.history.get(token_id) {
+            Some(history) => history,
+            None => {
+                return HistoryResponse {
+                    total: 0,
+                    next: None,
+                    data: vec![],
+                }
+            }
+        };
+
+        let total = history.len();
+        let (start, end) = page_bounds(total, start, count);
+
+        HistoryResponse {
+            total,
+            next: if end < total { Some(end) } else { None },
+            data: history
+                .iter()
+                .rev()
+                .skip(start)
+                .take(end - start)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// get a page of the tokens in a principal index
+    fn tokens_in(
+        &self,
+        index: &HashMap<Principal, BTreeSet<String>>,
+        principal: &Principal,
+        start: Option<usize>,
+        count: Option<usize>,
+    ) -> TokensResponse {
+        let ids = match index.get(principal) {
+            Some(ids) => ids,
+            None => {
+                return TokensResponse {
+                    total: 0,
+                    next: None,
+                    data: vec![],
+                }
+            }
+        };
+
+        let total = ids.len();
+        let (start, end) = page_bounds(total, start, count);
+
+        TokensResponse {
+            total,
+            next: if end < total { Some(end) } else { None },
+            data: ids
+                .iter()
+                .skip(start)
+                .take(end - start)
+                .filter_map(|id| self.db.get(id).cloned())
+                .collect(),
+        }
+    }
+
+    /// get a page of the tokens a buyer has open offers on
+    pub fn offers_of(
+        &self,
+        buyer: &Principal,
+        start: Option<usize>,
+        count: Option<usize>,
+    ) -> TokensResponse {
+        self.tokens_in(&self.offers_by, buyer, start, count)
+    }
+
+    /// get a page of the tokens a seller has listed
+    pub fn listings_of(
+        &self,
+        seller: &Principal,
+        start: Option<usize>,
+        count: Option<usize>,
+    ) -> TokensResponse {
+        self.tokens_in(&self.listings_by, seller, start, count)
+    }
+
+    /// get a page of an account's sales, newest first
+    fn sales_in(
+        index: &HashMap<Principal, Vec<AccountSale>>,
+        principal: &Principal,
+        start: Option<usize>,
+        count: Option<usize>,
+    ) -> SalesResponse {
+        let sales = match index.get(principal) {
+            Some(sales) => sales,
+            None => {
+                return SalesResponse {
+                    total: 0,
+                    next: None,
+                    data: vec![],
+                }
+            }
+        };
+
+        let total = sales.len();
+        let (start, end) = page_bounds(total, start, count);
+
+        SalesResponse {
+            total,
+            next: if end < total { Some(end) } else { None },
+            data: sales
+                .iter()
+                .rev()
+                .skip(start)
+                .take(end - start)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// get a page of the tokens a principal bought, newest first
+    pub fn purchases_of(
+        &self,
+        buyer: &Principal,
+        start: Option<usize>,
+        count: Option<usize>,
+    ) -> SalesResponse {
+        Self::sales_in(&self.purchases, buyer, start, count)
+    }
+
+    /// get a page of the tokens a principal sold, newest first
+    pub fn sales_of(
+        &self,
+        seller: &Principal,
+        start: Option<usize>,
+        count: Option<usize>,
+    ) -> SalesResponse {
+        Self::sales_in(&self.sales, seller, start, count)
+    }
+
+    /// exchange rates of the fungibles
+    pub fn get_rates(&self) -> Vec<(Principal, FungibleRate)> {
+        self.rates
+            .iter()
+            .map(|(fungible, rate)| (*fungible, rate.clone()))
+            .collect()
+    }
+
+    /// set or remove the exchange rate of a fungible, and re-sort the tokens with a price in
+    /// the fungible in the normalized price indexes. Returns the number of re-sorted tokens.
+    pub fn set_rate(&mut self, fungible: Principal, rate: Option<FungibleRate>) -> usize {
+        match rate {
+            Some(rate) => self.rates.insert(fungible, rate),
+            None => self.rates.remove(&fungible),
+        };
+
+        let token_ids: BTreeSet<String> = self
+            .fungible_index
+            .get(&fungible)
+            .into_iter()
+            .flat_map(|partition| partition.values())
+            .flat_map(|sorted| sorted.iter().cloned())
+            .collect();
+        for token_id in token_ids.iter() {
+            self.sort_normalized(token_id);
+        }
+
+        token_ids.len()
+    }
+
+    /// convert a price to the common unit, if the fungible has a rate
+    fn normalize(&self, price: &Nat, fungible: &Principal) -> Option<Nat> {
+        let rate = self.rates.get(fungible)?;
+        let unit = (0..rate.decimals).fold(Nat::from(1u8), |unit, _| unit * 10u8);
+
+        Some(price.clone() * rate.rate.clone() / unit)
+    }
+
+    /// re-sort a token in the normalized price indexes
+    fn sort_normalized(&mut self, token_id: &str) {
+        let token = match self.db.get(token_id) {
+            Some(token) => token,
+            None => return,
+        };
+
+        let listing = token
+            .price
+            .as_ref()
+            .zip(token.listing_fungible.as_ref())
+            .and_then(|(price, fungible)| self.normalize(price, fungible));
+        let offer = token
+            .offers
+            .iter()
+            .filter_map(|o| self.normalize(&o.price, &o.fungible))
+            .max();
+        let sale = token
+            .last_sale
+            .as_ref()
+            .and_then(|sale| self.normalize(&sale.price, &sale.fungible));
+
+        for (key, value) in [
+            ("normalized_listing_price", listing),
+            ("normalized_offer_price", offer),
+            ("normalized_sale_price", sale),
+        ] {
+            match value {
+                Some(value) => self.push_sort(key, token_id.to_string(), value),
+                None => self.remove(key, token_id),
+            }
+        }
+    }
+
+    /// collection statistics, with the sale windows ending at the current time
+    pub fn get_stats(&self, clock: &impl Clock) -> Stats {
+        let now = clock.now();
+        let fungibles: BTreeSet<&Principal> = self
+            .volume
+            .keys()
+            .chain(self.fungible_index.keys())
+            .collect();
+
+        Stats {
+            tokens: self.db.len() as u64,
+            owners: self.owned_by.len() as u64,
+            listed: self.sort_index["listing_price"].len() as u64,
+            fungibles: fungibles
+                .into_iter()
+                .map(|fungible| FungibleStats {
+                    fungible: *fungible,
+                    listed: self
+                        .fungible_index
+                        .get(fungible)
+                        .map_or(0, |partition| partition["listing_price"].len() as u64),
+                    floor: self.floor(fungible).cloned(),
+                    day: self.volume_since(fungible, now.saturating_sub(DAY)),
+                    week: self.volume_since(fungible, now.saturating_sub(WEEK)),
+                    all_time: self.volume.get(fungible).cloned().unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+
+    /// lowest listing price in a fungible
+    fn floor(&self, fungible: &Principal) -> Option<&Nat> {
+        let listings = &self.fungible_index.get(fungible)?["listing_price"];
+        listings.iter().next().and_then(|id| listings.value(id))
+    }
+
+    /// sum the sales in a fungible from the hourly candle containing `time`
+    fn volume_since(&self, fungible: &Principal, time: u64) -> Volume {
+        self.candles
+            .get(fungible)
+            .into_iter()
+            .flat_map(|candles| candles.range(time - time % HOUR..))
+            .fold(Volume::default(), |mut total, (_, candle)| {
+                total.sales += candle.sales;
+                total.volume += candle.volume.clone();
+                total
+            })
+    }
+
+    /// get the candles between two times, merging hourly candles into the interval. Candles
+    /// without activity are left out, and at most `TIMESERIES_LIMIT` candles are returned.
+    pub fn get_timeseries(
+        &self,
+        fungible: &Principal,
+        interval: Interval,
+        from: u64,
+        to: u64,
+    ) -> Vec<Candle> {
+        let hourly = match self.candles.get(fungible) {
+            Some(hourly) if from <= to => hourly,
+            _ => return vec![],
+        };
+
+        let size = interval.nanos();
+        let mut candles: Vec<Candle> = vec![];
+        for (time, candle) in hourly.range(from - from % size..=to) {
+            let start = time - time % size;
+            match candles.last_mut() {
+                Some(last) if last.start == start => last.merge(candle),
+                _ => {
+                    if candles.len() == TIMESERIES_LIMIT {
+                        break;
+                    }
+                    candles.push(Candle {
+                        start,
+                        ..candle.clone()
+                    });
+                }
+            }
+        }
+
+        candles
+    }
+
+    /// evaluate a trait filter expression against the trait maps
+    fn eval_filter(&self, filter: &TraitFilter) -> HashSet<String> {
+        match filter {
+            TraitFilter::Trait(key, value) => self
+                .trait_maps
+                .get(key)
+                .and_then(|values| values.get(value))
+                .map(|ids| ids.iter().cloned().collect())
+                .unwrap_or_default(),
+            TraitFilter::And(filters) => {
+                let mut filters = filters.iter();
+                match filters.next() {
+                    // empty intersection accepts everything
+                    None => self.db.keys().cloned().collect(),
+                    Some(first) => {
+                        let mut ids = self.eval_filter(first);
+                        for filter in filters {
+                            if ids.is_empty() {
+                                break;
+                            }
+                            let other = self.eval_filter(filter);
+                            ids.retain(|id| other.contains(id));
+                        }
+                        ids
+                    }
+                }
+            }
+            TraitFilter::Or(filters) => filters.iter().flat_map(|f| self.eval_filter(f)).collect(),
+            TraitFilter::TraitRange { key, min, max } => {
+                let values = match self.numeric_traits.get(key) {
+                    Some(values) => values,
+                    None => return HashSet::new(),
+                };
+                if let (Some(min), Some(max)) = (min, max) {
+                    if min > max {
+                        return HashSet::new();
+                    }
+                }
+
+                let start = min.clone().map_or(Bound::Unbounded, Bound::Included);
+                let end = max.clone().map_or(Bound::Unbounded, Bound::Included);
+                values
+                    .range((start, end))
+                    .flat_map(|(_, ids)| ids.iter().cloned())
+                    .collect()
+            }
+            TraitFilter::Not(filter) => {
+                let excluded = self.eval_filter(filter);
+                self.db
+                    .keys()
+                    .filter(|id| !excluded.contains(*id))
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
+
+    /// build the set of token ids in a sort index with a sort value within the range.
+    /// Sort indexes are ordered by sort value, so only the tokens in range are visited.
+    fn range_ids(
+        &self,
+        range: &RangeFilter,
+        fungible: Option<&Principal>,
+    ) -> Result<HashSet<String>, String> {
+        let sorted = if PRICE_KEYS.contains(&range.sort_key.as_str()) && fungible.is_some() {
+            self.sort_index_for(&range.sort_key, fungible)?
+        } else {
+            self.sort_index
+                .get(&range.sort_key)
+                .ok_or_else(|| format!("Range sort key not found: {}", range.sort_key))?
+        };
+
+        Ok(sorted
+            .range(range.min.as_ref(), range.max.as_ref())
+            .cloned()
+            .collect())
+    }
+
+    /// sort index of a key, or of the key's partition for a fungible
+    fn sort_index_for(
+        &self,
+        sort_key: &str,
+        fungible: Option<&Principal>,
+    ) -> Result<&SortIndex, String> {
+        match fungible {
+            None => self
+                .sort_index
+                .get(sort_key)
+                .ok_or_else(|| "Sort key not found".to_string()),
+            Some(fungible) => {
+                if !PRICE_KEYS.contains(&sort_key) {
+                    return Err(format!(
+                        "Sort key is not partitioned by fungible: {}",
+                        sort_key
+                    ));
+                }
+                self.fungible_index
+                    .get(fungible)
+                    .and_then(|partition| partition.get(sort_key))
+                    .ok_or_else(|| "Fungible not found".to_string())
+            }
+        }
+    }
+
+    /// build the set of token ids accepted by a request's traits, filter and ranges.
+    /// `traits` matches any of the given (key, value) pairs, and is combined with `filter`
+    /// and each of the `ranges` as an intersection. Price ranges are over the prices in
+    /// `fungible`, if provided. Returns `None` if the request is not filtered.
+    fn accepted_ids(
+        &self,
+        traits: &Option<Vec<(String, GenericValue)>>,
+        filter: &Option<TraitFilter>,
+        ranges: &Option<Vec<RangeFilter>>,
+        fungible: Option<&Principal>,
+    ) -> Result<Option<HashSet<String>>, String> {
+        let traits = traits.as_ref().map(|traits| {
+            TraitFilter::Or(
+                traits
+                    .iter()
+                    .map(|(key, value)| TraitFilter::Trait(key.clone(), value.clone()))
+                    .collect(),
+            )
+        });
+
+        let mut accepted_ids = match (traits, filter.clone()) {
+            (None, None) => None,
+            (Some(filter), None) | (None, Some(filter)) => Some(self.eval_filter(&filter)),
+            (Some(traits), Some(filter)) => {
+                Some(self.eval_filter(&TraitFilter::And(vec![traits, filter])))
+            }
+        };
+
+        for range in ranges.iter().flatten() {
+            let ids = self.range_ids(range, fungible)?;
+            accepted_ids = Some(match accepted_ids {
+                None => ids,
+                Some(mut accepted) => {
+                    accepted.retain(|id| ids.contains(id));
+                    accepted
+                }
+            });
+        }
+
+        Ok(accepted_ids)
+    }
+
+    /// count tokens for each trait value, over the whole collection and over the tokens
+    /// matching the request's sort key, traits and filter.
+    pub fn facets(&self, request: FacetRequest) -> FacetResponse {
+        let mut accepted_ids =
+            match self.accepted_ids(&request.traits, &request.filter, &request.ranges, None) {
+                Ok(ids) => ids,
+                Err(e) => {
+                    return FacetResponse {
+                        facets: vec![],
+                        error: Some(e),
+                    }
+                }
+            };
+
+        // restrict to the tokens in the sort index, if provided
+        if let Some(sort_key) = &request.sort_key {
+            match self.sort_index.get(sort_key) {
+                None => {
+                    return FacetResponse {
+                        facets: vec![],
+                        error: Some("Sort key not found".to_string()),
+                    }
+                }
+                Some(sorted) => {
+                    accepted_ids = Some(match accepted_ids {
+                        None => sorted.iter().cloned().collect(),
+                        Some(mut ids) => {
+                            ids.retain(|id| sorted.contains(id));
+                            ids
+                        }
+                    });
+                }
+            }
+        }
+
+        let mut facets: Vec<Facet> = self
+            .trait_maps
+            .iter()
+            .map(|(key, values)| {
+                let mut values: Vec<FacetValue> = values
+                    .iter()
+                    .map(|(value, ids)| FacetValue {
+                        value: value.clone(),
+                        total: ids.len(),
+                        count: match &accepted_ids {
+                            None => ids.len(),
+                            Some(accepted) => {
+                                ids.iter().filter(|id| accepted.contains(*id)).count()
+                            }
+                        },
+                    })
+                    .collect();
+                // most common values first
+                values.sort_by(|a, b| b.count.cmp(&a.count).then(b.total.cmp(&a.total)));
+
+                Facet {
+                    key: key.clone(),
+                    values,
+                }
+            })
+            .collect();
+        facets.sort_by(|a, b| a.key.cmp(&b.key));
+
+        FacetResponse {
+            facets,
+            error: None,
+        }
+    }
+
+    /// build an opaque cursor for a token in a sort index
+    fn cursor_at(sorted: &SortIndex, token_id: &str) -> String {
+        let value = sorted.value(token_id).cloned().unwrap_or_default();
+        // format the inner BigUint, as Nat's Display adds digit separators
+        format!("{}:{}", value.0, token_id)
+    }
+
+    /// parse a cursor into the (sort value, token id) entry it points at
+    fn parse_cursor(cursor: &str) -> Option<(Nat, String)> {
+        let (value, token_id) = cursor.split_once(':')?;
+        let value = Nat::parse(value.as_bytes()).ok()?;
+
+        Some((value, token_id.to_string()))
+    }
+
     pub fn query(&self, request: QueryRequest) -> QueryResponse {
+        self.query_within(request, None)
+    }
+
+    /// query the tokens of an owner, sorted and filtered like `query`
+    pub fn tokens_of(&self, owner: &Principal, request: QueryRequest) -> QueryResponse {
+        match self.owned_by.get(owner) {
+            Some(owned) => self.query_within(request, Some(owned)),
+            None => QueryResponse {
+                total: 0,
+                last_index: None,
+                cursor: None,
+                data: vec![],
+                error: None,
+            },
+        }
+    }
+
+    /// query sorted indexes, only accepting tokens in `within` if provided
+    fn query_within(
+        &self,
+        request: QueryRequest,
+        within: Option<&BTreeSet<String>>,
+    ) -> QueryResponse {
         let mut result = vec![];
         let mut size = request.count.unwrap_or(DEFAULT_PAGE_SIZE);
         if size > PAGE_SIZE_LIMIT {
             size = PAGE_SIZE_LIMIT;
         }
 
-        let indexes = &self.sort_index;
-        match indexes.get(&request.sort_key) {
+        match self.sort_index_for(&request.sort_key, request.fungible.as_ref()) {
             // if sort key is not found, return empty result
-            None => QueryResponse {
+            Err(e) => QueryResponse {
                 total: 0,
                 last_index: None,
+                cursor: None,
                 data: result,
-                error: Some("Sort key not found".to_string()),
-            },
-            Some(sorted) => {
-                // build hashset of accepted token ids from traits, if provided
-                let mut accepted_ids: HashSet<String> = HashSet::new();
-                if let Some(traits) = request.traits.clone() {
-                    for (key, value) in traits {
-                        // check if key val exists in trait map
-                        if let Some(trait_key) = self.trait_maps.get(&key) {
-                            if let Some(tokens) = trait_key.get(&value) {
-                                // if value exists, add to accepted_ids
-                                for id in tokens {
-                                    accepted_ids.insert(id.clone());
-                                }
-                            }
+                error: Some(e),
+            },
+            Ok(sorted) => {
+                // build hashset of accepted token ids from traits and filter, if provided
+                let accepted_ids = match self.accepted_ids(
+                    &request.traits,
+                    &request.filter,
+                    &request.ranges,
+                    request.fungible.as_ref(),
+                ) {
+                    Ok(ids) => ids,
+                    Err(e) => {
+                        return QueryResponse {
+                            total: 0,
+                            last_index: None,
+                            cursor: None,
+                            data: result,
+                            error: Some(e),
                         }
                     }
-
+                };
+                let accepted_ids = match (accepted_ids, within) {
+                    (accepted_ids, None) => accepted_ids,
+                    (None, Some(within)) => Some(within.iter().cloned().collect()),
+                    (Some(mut ids), Some(within)) => {
+                        ids.retain(|id| within.contains(id));
+                        Some(ids)
+                    }
+                };
+                if let Some(ids) = &accepted_ids {
                     // if no accepted_ids, return empty result
-                    if accepted_ids.is_empty() {
+                    if ids.is_empty() {
                         return QueryResponse {
                             total: 0,
                             last_index: None,
+                            cursor: None,
                             data: result,
                             error: Some(
                                 "No entries found under the specified trait key/vals".to_string(),
@@ -82,33 +730,74 @@ impl Database {
 
                 let max_len = sorted.len();
 
-                match request.reverse.unwrap_or(false) {
-                    false => {
-                        // descending order, default
-                        let last_index = request.last_index.unwrap_or(max_len);
-
-                        if last_index > max_len {
-                            // out of bounds, return nothing!
+                // cursor takes precedence over last_index
+                let cursor = match &request.cursor {
+                    Some(cursor) => match Self::parse_cursor(cursor) {
+                        Some(cursor) => Some(cursor),
+                        None => {
                             return QueryResponse {
                                 total: 0,
                                 last_index: None,
+                                cursor: None,
                                 data: result,
-                                error: Some("Page out of bounds".to_string()),
-                            };
+                                error: Some("Invalid cursor".to_string()),
+                            }
                         }
+                    },
+                    None => None,
+                };
+
+                // number of tokens in the sort index matching the filters
+                let total = match &accepted_ids {
+                    None => max_len,
+                    Some(ids) => ids.iter().filter(|id| sorted.contains(id)).count(),
+                };
+
+                match request.reverse.unwrap_or(false) {
+                    false => {
+                        // descending order, default. the position in the index is only known
+                        // when paging by last_index.
+                        let (mut index, tokens): (
+                            Option<usize>,
+                            Box<dyn Iterator<Item = &String>>,
+                        ) = match cursor {
+                            Some((value, token_id)) => {
+                                (None, Box::new(sorted.before(value, token_id).rev()))
+                            }
+                            None => {
+                                let last_index = request.last_index.unwrap_or(max_len);
+
+                                if last_index > max_len {
+                                    // out of bounds, return nothing!
+                                    return QueryResponse {
+                                        total: 0,
+                                        last_index: None,
+                                        cursor: None,
+                                        data: result,
+                                        error: Some("Page out of bounds".to_string()),
+                                    };
+                                }
 
-                        let mut scanned = 0;
-                        let mut index = last_index;
-                        while scanned < size && index > 0 {
-                            index -= 1;
-                            let token = &sorted[index];
+                                let tokens = sorted.iter().rev().skip(max_len - last_index);
+                                (Some(last_index), Box::new(tokens))
+                            }
+                        };
+
+                        let mut tokens = tokens.peekable();
+                        let mut last_token = None;
+                        while result.len() < size {
+                            let token = match tokens.next() {
+                                Some(token) => token,
+                                None => break,
+                            };
+                            index = index.map(|index| index - 1);
+                            last_token = Some(token);
 
                             // check if no filters, or if token is in the set of accepted ids
                             // do nothing if token is not in the set of accepted ids
-                            if request.traits.is_none() || accepted_ids.contains(token) {
+                            if accepted_ids.as_ref().is_none_or(|ids| ids.contains(token)) {
                                 match self.db.get(token) {
                                     Some(token) => {
-                                        scanned += 1;
                                         result.push(token.clone());
                                     }
                                     None => {
@@ -119,37 +808,59 @@ impl Database {
                             }
                         }
 
+                        let more = tokens.peek().is_some();
                         QueryResponse {
-                            total: max_len,
-                            last_index: if index > 0 { Some(index) } else { None },
+                            total,
+                            last_index: if more { index } else { None },
+                            cursor: match last_token {
+                                Some(token) if more => Some(Self::cursor_at(sorted, token)),
+                                _ => None,
+                            },
                             data: result,
                             error: None,
                         }
                     }
                     true => {
                         // ascending order
-                        let last_index = request.last_index.unwrap_or_default();
+                        let (mut index, tokens): (
+                            Option<usize>,
+                            Box<dyn Iterator<Item = &String>>,
+                        ) = match cursor {
+                            Some((value, token_id)) => {
+                                (None, Box::new(sorted.after(value, token_id)))
+                            }
+                            None => {
+                                let last_index = request.last_index.unwrap_or_default();
 
-                        if last_index > max_len {
-                            // out of bounds, return nothing!
-                            return QueryResponse {
-                                total: max_len,
-                                last_index: None,
-                                data: result,
-                                error: Some("Page out of bounds".to_string()),
-                            };
-                        }
+                                if last_index > max_len {
+                                    // out of bounds, return nothing!
+                                    return QueryResponse {
+                                        total,
+                                        last_index: None,
+                                        cursor: None,
+                                        data: result,
+                                        error: Some("Page out of bounds".to_string()),
+                                    };
+                                }
 
-                        let mut scanned = 0;
-                        let mut index = last_index;
-                        while scanned < size && index < max_len {
-                            let token = &sorted[index];
+                                (Some(last_index), Box::new(sorted.iter().skip(last_index)))
+                            }
+                        };
+
+                        let mut tokens = tokens.peekable();
+                        let mut last_token = None;
+                        while result.len() < size {
+                            let token = match tokens.next() {
+                                Some(token) => token,
+                                None => break,
+                            };
+                            index = index.map(|index| index + 1);
+                            last_token = Some(token);
 
                             // check if no filters, or if token is in the set of accepted ids
-                            if request.traits.is_none() || accepted_ids.contains(token) {
+                            if accepted_ids.as_ref().is_none_or(|ids| ids.contains(token)) {
                                 match self.get(token) {
                                     Some(token) => {
-                                        scanned += 1;
                                         result.push(token.clone());
                                     }
                                     None => {
@@ -158,14 +869,16 @@ impl Database {
                                 }
                                 // do nothing if token is not in the set of accepted ids
                             }
-
-                            index += 1;
-                            scanned += 1;
                         }
 
+                        let more = tokens.peek().is_some();
                         QueryResponse {
-                            total: max_len,
-                            last_index: if index < max_len { Some(index) } else { None },
+                            total,
+                            last_index: if more { index } else { None },
+                            cursor: match last_token {
+                                Some(token) if more => Some(Self::cursor_at(sorted, token)),
+                                _ => None,
+                            },
                             data: result,
                             error: None,
                         }
@@ -175,135 +888,76 @@ impl Database {
         }
     }
 
-    fn push_sort_sale(&mut self, token_id: String, price: Nat) {
-        let sorted = self.sort_index.get_mut("sale_price").unwrap();
-        let mut db = self.db.clone();
-
-        // check if key exists already
-        if !sorted.contains(&token_id) {
-            // key exists, just sort the array.
-            // Uses dmsort which is extremely efficient for mostly sorted vectors
-            sorted.push(token_id);
-            dmsort::sort_by_key(sorted, |id| {
-                match db.entry(id.to_string()).or_default().last_sale.clone() {
-                    Some(sale) => sale.price.clone(),
-                    None => 0.into(),
-                }
-            });
-            // old sort method
-            // sorted.sort_by_cached_key(|id| {
-            //     match db.entry(id.to_string()).or_default().last_sale.clone() {
-            //         Some(sale) => sale.price,
-            //         None => 0.into(),
-            //     }
-            // });
-        } else {
-            // not found, partition insert into sorted array
-            let index = sorted.partition_point(|id| {
-                let sale_price = match db.entry(id.clone()).or_default().last_sale.clone() {
-                    Some(sale) => sale.price.clone(),
-                    None => 0.into(),
-                };
-
-                sale_price < price
-            });
-
-            sorted.insert(index, token_id.clone());
-        }
+    /// insert or move a token in a sort index; O(log n)
+    fn push_sort(&mut self, key: &str, token_id: String, value: Nat) {
+        self.sort_index
+            .entry(key.to_string())
+            .or_default()
+            .insert(token_id, value);
     }
 
-    fn push_sort_listing(&mut self, token_id: String, amount: Nat) {
-        let sorted = self.sort_index.get_mut("listing_price").unwrap();
-        let mut db = self.db.clone();
-
-        // check if key exists already
-        if sorted.contains(&token_id) {
-            // key exists, just sort the array
-            // improvement: use dmsort which is extremely efficient at mostly sorted arrays
-            dmsort::sort_by_key(sorted, |id| {
-                db.entry(id.to_string())
-                    .or_default()
-                    .price
-                    .clone()
-                    .unwrap_or_default()
-            });
-            // old sort method
-            // sorted.sort_by_cached_key(|id| {
-            //     db.entry(id.to_string())
-            //         .or_default()
-            //         .price
-            //         .clone()
-            //         .unwrap_or_default()
-            // });
-        } else {
-            // not found, partition insert into sorted array
-            let index = sorted.partition_point(|id| {
-                db.entry(id.clone())
-                    .or_default()
-                    .price
-                    .clone()
-                    .unwrap_or_default()
-                    < amount
-            });
-
-            sorted.insert(index, token_id.clone());
+    /// remove a token from a sort index; O(log n)
+    fn remove(&mut self, key: &str, token_id: &str) {
+        if let Some(sort_index) = self.sort_index.get_mut(key) {
+            sort_index.remove(token_id);
         }
     }
 
-    fn push_sort_offer(&mut self, token_id: String, amount: Nat) {
-        let sorted = self.sort_index.get_mut("offer_price").unwrap();
-        let mut db = self.db.clone();
-
-        // check if key exists already
-        if sorted.contains(&token_id) {
-            // key exists, just sort the array
-            // improvement: use dmsort which is extremely efficient at mostly sorted arrays
-            dmsort::sort_by_key(sorted, |id| {
-                db.entry(id.to_string())
-                    .or_default()
-                    .best_offer
-                    .clone()
-                    .unwrap_or_default()
-            });
-            // old sort method
-            // sorted.sort_by_cached_key(|id| {
-            //     db.entry(id.to_string())
-            //         .or_default()
-            //         .best_offer
-            //         .clone()
-            //         .unwrap_or_default()
-            // });
-        } else {
-            // not found, partition insert into sorted array
-            let index = sorted.partition_point(|id| {
-                // research: should we use an Option here? would it be optimized for the null value to be 0 ?
-                db.entry(id.clone())
-                    .or_default()
-                    .best_offer
-                    .clone()
-                    .unwrap_or_default()
-                    < amount
-            });
+    /// index a price in the fungible's partition of a price sort index
+    fn push_fungible_sort(&mut self, fungible: Principal, key: &str, token_id: String, value: Nat) {
+        // define every price index of a new fungible, like `Database::new`
+        let partition = self.fungible_index.entry(fungible).or_insert_with(|| {
+            PRICE_KEYS
+                .iter()
+                .map(|key| (key.to_string(), SortIndex::default()))
+                .collect()
+        });
+        partition.get_mut(key).unwrap().insert(token_id, value);
+    }
 
-            sorted.insert(index, token_id.clone());
+    fn remove_fungible(&mut self, fungible: &Principal, key: &str, token_id: &str) {
+        if let Some(sort_index) = self
+            .fungible_index
+            .get_mut(fungible)
+            .and_then(|partition| partition.get_mut(key))
+        {
+            sort_index.remove(token_id);
         }
     }
 
-    fn shift_or_push(&mut self, key: &str, id: String) {
-        // remove and push; time based indexes
-        let sort_index = self.sort_index.entry(key.to_string()).or_default();
-        // todo: iter from reverse until found, remove index, and push to end
-        sort_index.retain(|token| *token != id);
-        sort_index.push(id.clone());
-    }
+    /// re-sort a token in a fungible's offer price index, by its best offer in the fungible
+    fn sort_fungible_offers(&mut self, token_id: &str, fungible: Principal) {
+        let best_offer = self.db.get(token_id).and_then(|token| {
+            token
+                .offers
+                .iter()
+                .filter(|o| o.fungible == fungible)
+                .map(|o| o.price.clone())
+                .max()
+        });
 
-    fn remove(&mut self, key: &str, id: String) {
-        // remove; time based indexes
-        let sort_index = self.sort_index.entry(key.to_string()).or_default();
-        sort_index.retain(|token| *token != id);
+        match best_offer {
+            None => self.remove_fungible(&fungible, "offer_price", token_id),
+            Some(best_offer) => {
+                self.push_fungible_sort(fungible, "offer_price", token_id.to_string(), best_offer)
+            }
+        }
     }
 
     fn push_trait(&mut self, token_id: String, name: String, value: GenericValue) {
+        // numeric values are also indexed by value, for range filters
+        if let Some(number) = value.as_int() {
+            let numeric_index = self
+                .numeric_traits
+                .entry(name.clone())
+                .or_default()
+                .entry(number)
+                .or_default();
+            if !numeric_index.contains(&token_id) {
+                numeric_index.push(token_id.clone());
+            }
+        }
+
         let trait_index = self
             .trait_maps
             .entry(name)
@@ -315,252 +969,2147 @@ impl Database {
         }
     }
 
-    pub fn index_event(&mut self, event: Event) -> Result<(), &'static str> {
-        let mut token = self.db.entry(event.token_id.clone()).or_default();
-        let time = time();
-
-        match event.operation.as_str() {
-            "mint" => {
-                // load new metadata into canister
-                token.id = event.token_id.clone();
-                token.traits = event.traits.clone();
-
-                if let Some(traits) = event.traits.clone() {
-                    for (k, v) in traits.clone() {
-                        self.push_trait(event.token_id.clone(), k, v);
+    /// remove a token from a trait bucket, deleting the bucket once empty
+    fn remove_trait(&mut self, token_id: &str, name: &str, value: &GenericValue) {
+        if let Some(number) = value.as_int() {
+            if let Some(values) = self.numeric_traits.get_mut(name) {
+                if let Some(ids) = values.get_mut(&number) {
+                    ids.retain(|id| id != token_id);
+                    if ids.is_empty() {
+                        values.remove(&number);
                     }
                 }
+                if values.is_empty() {
+                    self.numeric_traits.remove(name);
+                }
             }
+        }
 
-            "makeListing" => {
-                // update db entry
-                token.price = event.price.clone();
-                token.last_listing = Some(time.into());
+        if let Some(values) = self.trait_maps.get_mut(name) {
+            if let Some(ids) = values.get_mut(value) {
+                ids.retain(|id| id != token_id);
+                if ids.is_empty() {
+                    values.remove(value);
+                }
+            }
+            if values.is_empty() {
+                self.trait_maps.remove(name);
+            }
+        }
+    }
 
-                let price = event.price.unwrap_or(Nat::from(0));
+    /// replace a token's traits, moving the token out of the buckets of changed or removed
+    /// traits and into the buckets of the new values
+    fn set_traits(&mut self, token_id: &str, traits: Option<HashMap<String, GenericValue>>) {
+        let token = self.db.entry(token_id.to_string()).or_default();
+        let old = std::mem::replace(&mut token.traits, traits.clone()).unwrap_or_default();
+        let new = traits.unwrap_or_default();
 
-                // index listing price
-                self.push_sort_listing(event.token_id.clone(), price);
-                // update last listing index
-                self.shift_or_push("last_listing", event.token_id.clone());
+        for (name, value) in old.iter() {
+            if new.get(name) != Some(value) {
+                self.remove_trait(token_id, name, value);
             }
-            "cancelListing" => {
-                // update db entry
-                token.price = None;
-
-                // remove from listing index
-                self.remove("listing_price", event.token_id.clone());
-                // remove from last listing index
-                self.remove("last_listing", event.token_id.clone());
+        }
+        for (name, value) in new {
+            if old.get(&name) != Some(&value) {
+                self.push_trait(token_id.to_string(), name, value);
             }
+        }
+    }
 
-            "makeOffer" => {
-                // update db entry
-                token.last_offer = Some(time.into());
-                token.best_offer = event.price.clone();
-                let price = event.price.unwrap_or(Nat::from(0));
-
-                token.offers.push(Offer {
-                    buyer: event.buyer.unwrap_or(Principal::anonymous()),
-                    fungible: event
-                        .fungible_id
-                        .unwrap_or(Principal::management_canister()),
-                    price: price.clone(),
-                });
+    /// remove a token from the db, and from every sort, trait, principal and history index
+    fn burn(&mut self, token_id: &str) {
+        let token = match self.db.remove(token_id) {
+            Some(token) => token,
+            None => return,
+        };
 
-                // index offer price
-                self.push_sort_offer(event.token_id.clone(), price);
-
-                // TODO: offer price index
-                self.shift_or_push("last_offer", event.token_id.clone());
-            }
-            "cancelOffer" => {
-                // TODO:
-                // remove from last offer index and offer price index if its the only one left (cancelled only offer)
-                // If not, leave it in the index, and re-sort the offer price index (cancelled offer but others remain)
-                match event.buyer {
-                    Some(buyer) => {
-                        token.offers.retain(|o| o.buyer != buyer);
-
-                        match &token.best_offer {
-                            Some(best_offer) => match token.offers.last() {
-                                Some(offer) => {
-                                    if offer.price.clone() > best_offer.clone() {
-                                        token.best_offer = Some(offer.price.clone());
-                                    }
-                                }
-                                None => {
-                                    token.best_offer = None;
-                                }
-                            },
-                            None => unreachable!(),
-                        }
-                    }
-                    None => {}
-                }
+        for sorted in self.sort_index.values_mut() {
+            sorted.remove(token_id);
+        }
+        for sorted in self
+            .fungible_index
+            .values_mut()
+            .flat_map(|p| p.values_mut())
+        {
+            sorted.remove(token_id);
+        }
+        for (name, value) in token.traits.iter().flatten() {
+            self.remove_trait(token_id, name, value);
+        }
 
-                if token.offers.is_empty() {
-                    // remove from last offer and offer price indexes if no more offers on the token
-                    self.remove("last_offer", event.token_id.clone());
-                    self.remove("offer_price", event.token_id.clone());
-                } else {
-                    // re-sort offer price index
-
-                    // find best offer
-                    let mut best_offer = None;
-                    for offer in token.offers.iter() {
-                        if best_offer.is_none() {
-                            best_offer = Some(offer.price.clone());
-                        } else if offer.price.clone() > best_offer.clone().unwrap() {
-                            best_offer = Some(offer.price.clone());
-                        }
-                    }
+        for offer in token.offers.iter() {
+            unindex_principal(&mut self.offers_by, &offer.buyer, token_id);
+        }
+        if let Some(seller) = token.seller {
+            unindex_principal(&mut self.listings_by, &seller, token_id);
+        }
+        if let Some(owner) = token.owner {
+            unindex_principal(&mut self.owned_by, &owner, token_id);
+        }
 
-                    // update best offer
-                    token.best_offer = best_offer.clone();
+        self.history.remove(token_id);
+    }
 
-                    // sort offer price index
-                    self.push_sort_offer(event.token_id.clone(), best_offer.unwrap());
-                }
-            }
+    /// record a token activity, keeping the most recent `TOKEN_HISTORY_LIMIT` entries
+    fn push_history(&mut self, token_id: &str, time: u64, activity: Activity) {
+        let history = self.history.entry(token_id.to_string()).or_default();
 
-            "directBuy" => {
-                // update db entry
-                token.last_sale = Some(Sale {
-                    buyer: event.buyer.unwrap_or(Principal::anonymous()),
-                    fungible: event
-                        .fungible_id
-                        .unwrap_or(Principal::management_canister()),
-                    price: event.price.clone().unwrap_or_default(),
-                    time: time.into(),
-                });
-                token.price = None;
-
-                match event.buyer {
-                    Some(buyer) => {
-                        if !token.offers.is_empty() {
-                            token.offers.retain(|o| o.buyer != buyer);
-
-                            match &token.best_offer {
-                                Some(best_offer) => match token.offers.last() {
-                                    Some(offer) => {
-                                        if offer.price.clone() > best_offer.clone() {
-                                            token.best_offer = Some(offer.price.clone());
-                                        }
-                                    }
-                                    None => {
-                                        token.best_offer = None;
-                                    }
-                                },
-                                None => {}
-                            }
+        // keep time order for events received out of order
+        let index = history.partition_point(|entry| entry.time <= time);
+        history.insert(index, HistoryEntry { time, activity });
 
-                            if token.offers.is_empty() {
-                                // remove from last offer and offer price indexes if no more offers on the token
-                                self.remove("last_offer", event.token_id.clone());
-                                self.remove("offer_price", event.token_id.clone());
-                            } else {
-                                // re-sort offer price index
-
-                                // find best offer
-                                let mut best_offer = None;
-                                for offer in token.offers.iter() {
-                                    if best_offer.is_none() {
-                                        best_offer = Some(offer.price.clone());
-                                    } else if offer.price.clone() > best_offer.clone().unwrap() {
-                                        best_offer = Some(offer.price.clone());
-                                    }
-                                }
+        if history.len() > TOKEN_HISTORY_LIMIT {
+            history.remove(0);
+        }
+    }
 
-                                // update best offer
-                                token.best_offer = best_offer.clone();
+    /// hourly candle of a fungible containing `time`
+    fn candle(&mut self, fungible: Principal, time: u64) -> &mut Candle {
+        let start = time - time % HOUR;
+        self.candles
+            .entry(fungible)
+            .or_default()
+            .entry(start)
+            .or_insert_with(|| Candle {
+                start,
+                ..Candle::default()
+            })
+    }
 
-                                // sort offer price index
-                                self.push_sort_offer(event.token_id.clone(), best_offer.unwrap());
-                            }
-                        }
+    /// add a sale to its candle and the running volume of its fungible
+    fn push_sale(&mut self, fungible: Principal, time: u64, price: &Nat) {
+        self.candle(fungible, time).push_sale(price);
+
+        let volume = self.volume.entry(fungible).or_default();
+        volume.sales += 1;
+        volume.volume += price.clone();
+    }
+
+    /// set or clear the seller of a token's listing, and move the token between seller indexes
+    fn set_seller(&mut self, token_id: &str, seller: Option<Principal>) {
+        let token = self.db.entry(token_id.to_string()).or_default();
+        if let Some(old) = std::mem::replace(&mut token.seller, seller) {
+            unindex_principal(&mut self.listings_by, &old, token_id);
+        }
+        if let Some(seller) = seller {
+            self.listings_by
+                .entry(seller)
+                .or_default()
+                .insert(token_id.to_string());
+        }
+    }
+
+    /// set the owner of a token as of `time`, and move the token between owner indexes.
+    /// Returns false if the token changed owners after `time`, leaving the current owner.
+    fn set_owner(&mut self, token_id: &str, owner: Principal, time: u64) -> bool {
+        let token = self.db.entry(token_id.to_string()).or_default();
+        if token.last_transfer.as_ref().is_some_and(|t| *t > time) {
+            return false;
+        }
+
+        token.last_transfer = Some(time.into());
+        if let Some(old) = token.owner.replace(owner) {
+            unindex_principal(&mut self.owned_by, &old, token_id);
+        }
+        self.owned_by
+            .entry(owner)
+            .or_default()
+            .insert(token_id.to_string());
+        true
+    }
+
+    /// remove a token's listing from the db entry and listing indexes
+    fn cancel_listing(&mut self, token_id: &str) {
+        let token = self.db.entry(token_id.to_string()).or_default();
+        token.price = None;
+        if let Some(fungible) = token.listing_fungible.take() {
+            self.remove_fungible(&fungible, "listing_price", token_id);
+        }
+        self.set_seller(token_id, None);
+
+        // remove from listing index
+        self.remove("listing_price", token_id);
+        // remove from last listing index
+        self.remove("last_listing", token_id);
+    }
+
+    /// record a sale for the buyer and seller
+    fn push_account_sale(&mut self, sale: AccountSale) {
+        let mut accounts = vec![(&mut self.purchases, sale.buyer)];
+        if let Some(seller) = sale.seller {
+            accounts.push((&mut self.sales, seller));
+        }
+
+        for (index, principal) in accounts {
+            let sales = index.entry(principal).or_default();
+            // keep time order for events received out of order
+            let position = sales.partition_point(|entry| entry.time <= sale.time);
+            sales.insert(position, sale.clone());
+        }
+    }
+
+    fn remove_offer(&mut self, token_id: String, buyer: Principal) {
+        let token = self.db.entry(token_id.clone()).or_default();
+        let fungibles: HashSet<Principal> = token
+            .offers
+            .iter()
+            .filter(|o| o.buyer == buyer)
+            .map(|o| o.fungible)
+            .collect();
+        token.offers.retain(|o| o.buyer != buyer);
+        unindex_principal(&mut self.offers_by, &buyer, &token_id);
+
+        // find best offer of the remaining offers
+        token.best_offer = token.offers.iter().map(|o| o.price.clone()).max();
+
+        match token.best_offer.clone() {
+            None => {
+                // remove from last offer and offer price indexes if no more offers on the token
+                self.remove("last_offer", &token_id);
+                self.remove("offer_price", &token_id);
+            }
+            Some(best_offer) => {
+                // re-sort offer price index
+                self.push_sort("offer_price", token_id.clone(), best_offer);
+            }
+        }
+
+        for fungible in fungibles {
+            self.sort_fungible_offers(&token_id, fungible);
+        }
+    }
+
+    /// check if an event can be indexed, without modifying the database.
+    ///
+    /// `pending_minted` and `pending_burned` hold token ids minted and burned by earlier events
+    /// of the same batch. A token minted after it was burned is only in `pending_minted`.
+    pub fn validate_event(
+        &self,
+        event: &Event,
+        pending_minted: &HashSet<String>,
+        pending_burned: &HashSet<String>,
+    ) -> Result<(), &'static str> {
+        match event.operation {
+            Operation::Mint { .. } => Ok(()),
+            _ => {
+                // any other operation requires the token to be indexed first
+                let indexed = self.db.contains_key(&event.token_id)
+                    && !pending_burned.contains(&event.token_id);
+                if indexed || pending_minted.contains(&event.token_id) {
+                    Ok(())
+                } else {
+                    Err("Token not indexed")
+                }
+            }
+        }
+    }
+
+    /// check if an event with the same source id was already applied
+    pub fn is_applied(&self, event: &Event) -> bool {
+        match event.event_id {
+            Some(id) => self.applied_ids.contains(&id),
+            None => false,
+        }
+    }
+
+    pub fn index_event(
+        &mut self,
+        event: Event,
+        clock: &impl Clock,
+    ) -> Result<IndexResult, &'static str> {
+        if self.is_applied(&event) {
+            return Ok(IndexResult::AlreadyApplied);
+        }
+        self.validate_event(&event, &HashSet::new(), &HashSet::new())?;
+
+        // prefer the time the event happened at, ie. when backfilling history
+        let time = event.time.unwrap_or_else(|| clock.now());
+        self.apply_event(event.clone(), time);
+        if let Some(id) = event.event_id {
+            self.applied_ids.insert(id);
+        }
+        self.events.push(LoggedEvent {
+            seq: self.events.len() as u64,
+            time,
+            event,
+        });
+
+        Ok(IndexResult::Applied)
+    }
+
+    /// drop every index and replay the event log, returning the number of replayed events
+    pub fn rebuild_indexes(&mut self) -> usize {
+        let events = std::mem::take(&mut self.events);
+        let rates = std::mem::take(&mut self.rates);
+        *self = Database::new();
+        // rates are not events, keep them to normalize the replayed prices
+        self.rates = rates;
+
+        for entry in events.iter() {
+            self.apply_event(entry.event.clone(), entry.time);
+            if let Some(id) = entry.event.event_id {
+                self.applied_ids.insert(id);
+            }
+        }
+
+        self.events = events;
+        self.events.len()
+    }
+
+    /// apply a validated event to the indexes, as if it happened at `time`.
+    ///
+    /// Events are applied in the order they are received, but times only move forward, so an
+    /// older event received late does not move a token back in the time based indexes.
+    fn apply_event(&mut self, event: Event, time: u64) {
+        let token = self.db.entry(event.token_id.clone()).or_default();
+        // set when the event changes the token's current listing
+        let mut moves_floor = false;
+        let burned = matches!(event.operation, Operation::Burn);
+        let listed_in = token.listing_fungible;
+
+        match event.operation {
+            Operation::Mint { traits, to } => {
+                // load new metadata into canister
+                token.id = event.token_id.clone();
+                self.set_traits(&event.token_id, traits);
+
+                if let Some(to) = to {
+                    self.set_owner(&event.token_id, to, time);
+                }
+            }
+            Operation::Transfer { to } => {
+                // an older transfer received late does not move the token back
+                let listed_before = !is_stale_listing(token, time);
+                if self.set_owner(&event.token_id, to, time) && listed_before {
+                    // a listing made by the previous owner is no longer valid
+                    self.cancel_listing(&event.token_id);
+                    moves_floor = listed_in.is_some();
+                }
+            }
+            Operation::Burn => {
+                moves_floor = listed_in.is_some();
+                self.burn(&event.token_id);
+            }
+            Operation::UpdateMetadata { traits } => {
+                self.set_traits(&event.token_id, traits);
+            }
+
+            Operation::MakeListing { price } => {
+                let fungible = event
+                    .fungible_id
+                    .unwrap_or(Principal::management_canister());
+
+                // a listing older than the current listing or last sale is only history
+                if !is_stale_listing(token, time) {
+                    moves_floor = true;
+                    // update db entry
+                    token.price = Some(price.clone());
+                    token.last_listing = Some(time.into());
+                    let relisted = token.listing_fungible.replace(fungible);
+                    // the owner lists the token, unless the event has a seller
+                    let seller = event.seller.or(token.owner);
+
+                    // index listing price
+                    self.push_sort("listing_price", event.token_id.clone(), price.clone());
+                    // move the listing to the fungible's listing price index
+                    if let Some(relisted) = relisted.filter(|f| *f != fungible) {
+                        self.remove_fungible(&relisted, "listing_price", &event.token_id);
                     }
-                    None => {}
+                    self.push_fungible_sort(
+                        fungible,
+                        "listing_price",
+                        event.token_id.clone(),
+                        price.clone(),
+                    );
+                    // update last listing index
+                    self.push_sort("last_listing", event.token_id.clone(), time.into());
+                    self.set_seller(&event.token_id, seller);
                 }
 
-                // update sale price index
-                self.push_sort_sale(event.token_id.clone(), event.price.unwrap());
-                // remove from listing index
-                self.remove("listing_price", event.token_id.clone());
-                // update last sale index
-                self.shift_or_push("last_sale", event.token_id.clone());
+                self.push_history(&event.token_id, time, Activity::Listing { fungible, price });
+            }
+            Operation::CancelListing => {
+                if !is_stale_listing(token, time) {
+                    moves_floor = listed_in.is_some();
+                    self.cancel_listing(&event.token_id);
+                }
             }
-            "acceptOffer" => {
-                // update db entry
-                token.last_sale = Some(Sale {
-                    buyer: event.buyer.unwrap_or(Principal::anonymous()),
-                    fungible: event
-                        .fungible_id
-                        .unwrap_or(Principal::management_canister()),
-                    price: event.price.clone().unwrap_or_default(),
-                    time: time.into(),
-                });
-                token.price = None;
-
-                // TODO: mirror offer removal logic
-                match event.buyer {
-                    Some(buyer) => {
-                        token.offers.retain(|o| o.buyer != buyer);
-
-                        match &token.best_offer {
-                            Some(best_offer) => match token.offers.last() {
-                                Some(offer) => {
-                                    if offer.price.clone() > best_offer.clone() {
-                                        token.best_offer = Some(offer.price.clone());
-                                    }
-                                }
-                                None => {
-                                    token.best_offer = None;
-                                }
-                            },
-                            None => {}
-                        }
 
-                        if token.offers.is_empty() {
-                            // remove from last offer and offer price indexes if no more offers on the token
-                            self.remove("last_offer", event.token_id.clone());
-                            self.remove("offer_price", event.token_id.clone());
-                        } else {
-                            // re-sort offer price index
-
-                            // find best offer
-                            let mut best_offer = None;
-                            for offer in token.offers.iter() {
-                                if best_offer.is_none() {
-                                    best_offer = Some(offer.price.clone());
-                                } else if offer.price.clone() > best_offer.clone().unwrap() {
-                                    best_offer = Some(offer.price.clone());
-                                }
-                            }
+            Operation::MakeOffer { buyer, price } => {
+                let fungible = event
+                    .fungible_id
+                    .unwrap_or(Principal::management_canister());
 
-                            // update best offer
-                            token.best_offer = best_offer.clone();
+                // an offer older than the buyer's current offer is only history
+                if !is_stale_offer(token, &buyer, time) {
+                    // update db entry
+                    let offered = latest(token.last_offer.as_ref(), time);
+                    token.last_offer = Some(offered.clone());
 
-                            // sort offer price index
-                            self.push_sort_offer(event.token_id.clone(), best_offer.unwrap());
-                        }
+                    // a new offer from the buyer replaces their previous offer
+                    let replaced = token
+                        .offers
+                        .iter()
+                        .find(|o| o.buyer == buyer)
+                        .map(|o| o.fungible);
+                    token.offers.retain(|o| o.buyer != buyer);
+                    token.offers.push(Offer {
+                        buyer,
+                        fungible,
+                        price: price.clone(),
+                        time: time.into(),
+                    });
+
+                    // the best offer is the highest offer, not the latest one
+                    let best_offer = token.offers.iter().map(|o| o.price.clone()).max();
+                    token.best_offer = best_offer.clone();
+
+                    // index offer price
+                    if let Some(best_offer) = best_offer {
+                        self.push_sort("offer_price", event.token_id.clone(), best_offer);
+                    }
+                    self.offers_by
+                        .entry(buyer)
+                        .or_default()
+                        .insert(event.token_id.clone());
+                    if let Some(replaced) = replaced.filter(|f| *f != fungible) {
+                        self.sort_fungible_offers(&event.token_id, replaced);
                     }
-                    None => {}
+                    self.sort_fungible_offers(&event.token_id, fungible);
+                    // update last offer index
+                    self.push_sort("last_offer", event.token_id.clone(), offered);
                 }
 
-                // update sale price index
-                self.push_sort_sale(event.token_id.clone(), event.price.unwrap());
-                // remove from listing price index
-                self.remove("listing_price", event.token_id.clone());
-                // update last sale index
-                self.shift_or_push("last_sale", event.token_id.clone());
+                self.push_history(
+                    &event.token_id,
+                    time,
+                    Activity::Offer {
+                        buyer,
+                        fungible,
+                        price,
+                    },
+                );
             }
-            _ => {
-                return Err("invalid operation");
+            Operation::CancelOffer { buyer } => {
+                // a cancel older than the buyer's current offer does not remove it
+                if !is_stale_offer(token, &buyer, time) {
+                    // remove the offer, and update or remove the offer indexes
+                    self.remove_offer(event.token_id.clone(), buyer);
+                }
             }
+
+            Operation::DirectBuy { buyer, price } | Operation::AcceptOffer { buyer, price } => {
+                // an older sale received late does not replace the last sale
+                let is_last_sale = token
+                    .last_sale
+                    .as_ref()
+                    .is_none_or(|sale| sale.time <= time);
+                // nor does it close a newer listing, or fulfill the buyer's newer offer
+                let closes_listing = !is_stale_listing(token, time);
+                let fulfills_offer = !is_stale_offer(token, &buyer, time);
+
+                // update db entry
+                let fungible = event
+                    .fungible_id
+                    .unwrap_or(Principal::management_canister());
+                let seller = event.seller.or(token.seller).or(token.owner);
+                let last_sale_fungible = token.last_sale.as_ref().map(|sale| sale.fungible);
+                if is_last_sale {
+                    token.last_sale = Some(Sale {
+                        buyer,
+                        fungible,
+                        price: price.clone(),
+                        time: time.into(),
+                    });
+                }
+
+                // the buyer's offer (if any) is fulfilled by the sale
+                if fulfills_offer {
+                    self.remove_offer(event.token_id.clone(), buyer);
+                }
+
+                if closes_listing {
+                    moves_floor = listed_in.is_some();
+                    // remove from the listing indexes, keeping the last listing time
+                    let token = self.db.get_mut(&event.token_id).unwrap();
+                    token.price = None;
+                    let listing_fungible = token.listing_fungible.take();
+                    self.remove("listing_price", &event.token_id);
+                    if let Some(listing_fungible) = listing_fungible {
+                        self.remove_fungible(&listing_fungible, "listing_price", &event.token_id);
+                    }
+                    self.set_seller(&event.token_id, None);
+                }
+                self.set_owner(&event.token_id, buyer, time);
+                if is_last_sale {
+                    // update sale price index
+                    self.push_sort("sale_price", event.token_id.clone(), price.clone());
+                    if let Some(last) = last_sale_fungible.filter(|f| *f != fungible) {
+                        self.remove_fungible(&last, "sale_price", &event.token_id);
+                    }
+                    self.push_fungible_sort(
+                        fungible,
+                        "sale_price",
+                        event.token_id.clone(),
+                        price.clone(),
+                    );
+                    // update last sale index
+                    self.push_sort("last_sale", event.token_id.clone(), time.into());
+                }
+
+                // late sales still count towards the volume of their own candle
+                self.push_sale(fungible, time, &price);
+                self.push_account_sale(AccountSale {
+                    token_id: event.token_id.clone(),
+                    time,
+                    buyer,
+                    seller,
+                    fungible,
+                    price: price.clone(),
+                });
+
+                self.push_history(
+                    &event.token_id,
+                    time,
+                    Activity::Sale {
+                        buyer,
+                        fungible,
+                        price,
+                    },
+                );
+            }
+        }
+
+        if moves_floor {
+            // record the floor of the fungibles the token was and is listed in. the floor
+            // changes now, so an event received late does not rewrite past candles.
+            let now = time.max(self.last_time);
+            let listed_after = self
+                .db
+                .get(&event.token_id)
+                .and_then(|token| token.listing_fungible);
+            let fungibles: BTreeSet<Principal> =
+                listed_in.into_iter().chain(listed_after).collect();
+            for fungible in fungibles {
+                let floor = self.floor(&fungible).cloned();
+                self.candle(fungible, now).push_floor(floor.as_ref());
+            }
+        }
+        self.last_time = self.last_time.max(time);
+
+        if !burned {
+            self.sort_normalized(&event.token_id);
+
+            let modified = latest(self.sort_index["all"].value(&event.token_id), time);
+            self.push_sort("all", event.token_id.clone(), modified);
+        }
+    }
+}
+
+const WEEK: u64 = 7 * DAY;
+
+/// check if an event at `time` is older than a token's listing state, ie. its last listing
+/// or sale
+fn is_stale_listing(token: &TokenData, time: u64) -> bool {
+    token
+        .last_listing
+        .as_ref()
+        .is_some_and(|listed| *listed > time)
+        || token
+            .last_sale
+            .as_ref()
+            .is_some_and(|sale| sale.time > time)
+}
+
+/// check if an event at `time` is older than the buyer's current offer on a token
+fn is_stale_offer(token: &TokenData, buyer: &Principal, time: u64) -> bool {
+    token
+        .offers
+        .iter()
+        .any(|offer| offer.buyer == *buyer && offer.time > time)
+}
+
+/// sort keys of the price indexes, partitioned by fungible
+const PRICE_KEYS: [&str; 3] = ["listing_price", "offer_price", "sale_price"];
+
+/// offset and end of a page of `total` items
+fn page_bounds(total: usize, start: Option<usize>, count: Option<usize>) -> (usize, usize) {
+    let mut size = count.unwrap_or(DEFAULT_PAGE_SIZE);
+    if size > PAGE_SIZE_LIMIT {
+        size = PAGE_SIZE_LIMIT;
+    }
+
+    let start = start.unwrap_or_default().min(total);
+    (start, (start + size).min(total))
+}
+
+/// remove a token from a principal index, dropping the principal's entry once empty
+fn unindex_principal(
+    index: &mut HashMap<Principal, BTreeSet<String>>,
+    principal: &Principal,
+    token_id: &str,
+) {
+    if let Some(ids) = index.get_mut(principal) {
+        ids.remove(token_id);
+        if ids.is_empty() {
+            index.remove(principal);
         }
-        self.shift_or_push("all", event.token_id.clone());
+    }
+}
+
+/// the most recent of a token's current time and an event time
+fn latest(current: Option<&Nat>, time: u64) -> Nat {
+    let time = Nat::from(time);
+    match current {
+        Some(current) if *current > time => current.clone(),
+        _ => time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn event(token_id: &str, operation: Operation) -> Event {
+        Event {
+            nft_canister_id: Principal::management_canister(),
+            fungible_id: None,
+            token_id: token_id.to_string(),
+            operation,
+            seller: None,
+            event_id: None,
+            time: None,
+        }
+    }
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
+
+    fn text(value: &str) -> GenericValue {
+        GenericValue::TextContent(value.to_string())
+    }
+
+    fn mint(db: &mut Database, clock: &MockClock, token_id: &str, traits: &[(&str, GenericValue)]) {
+        let traits = traits
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        index(
+            db,
+            clock,
+            token_id,
+            Operation::Mint {
+                traits: Some(traits),
+                to: None,
+            },
+        );
+    }
+
+    fn index(db: &mut Database, clock: &MockClock, token_id: &str, operation: Operation) {
+        clock.advance(1);
+        db.index_event(event(token_id, operation), clock).unwrap();
+    }
+
+    fn request(sort_key: &str) -> QueryRequest {
+        QueryRequest {
+            sort_key: sort_key.to_string(),
+            last_index: None,
+            cursor: None,
+            count: None,
+            traits: None,
+            filter: None,
+            ranges: None,
+            reverse: None,
+            fungible: None,
+        }
+    }
+
+    /// token ids of a query, in order
+    fn ids(db: &Database, request: QueryRequest) -> Vec<String> {
+        let response = db.query(request);
+        assert!(response.error.is_none(), "{:?}", response.error);
+        response.data.into_iter().map(|token| token.id).collect()
+    }
+
+    /// database with tokens "0".."4", with the base traits Gold, Gold, Silver, Silver, Bronze
+    /// and levels 0..4
+    fn setup() -> (Database, MockClock) {
+        let mut db = Database::new();
+        let clock = MockClock::new(0);
+        let bases = ["Gold", "Gold", "Silver", "Silver", "Bronze"];
+        for (i, base) in bases.iter().enumerate() {
+            mint(
+                &mut db,
+                &clock,
+                &i.to_string(),
+                &[
+                    ("base", text(base)),
+                    ("level", GenericValue::Nat8Content(i as u8)),
+                ],
+            );
+        }
+        (db, clock)
+    }
+
+    #[test]
+    fn mint_indexes_traits() {
+        let (db, _) = setup();
+
+        let token = db.get(&"2".to_string()).unwrap();
+        assert_eq!(token.id, "2");
+        assert_eq!(token.traits.as_ref().unwrap()["base"], text("Silver"));
+        assert_eq!(
+            db.trait_maps["base"][&text("Gold")],
+            vec!["0".to_string(), "1".to_string()]
+        );
+        assert_eq!(ids(&db, request("all")), vec!["4", "3", "2", "1", "0"]);
+    }
+
+    #[test]
+    fn non_mint_requires_indexed_token() {
+        let (mut db, clock) = setup();
+
+        let result = db.index_event(event("9", Operation::CancelListing), &clock);
+        assert_eq!(result, Err("Token not indexed"));
+        assert!(db.get(&"9".to_string()).is_none());
+    }
+
+    #[test]
+    fn make_and_cancel_listing() {
+        let (mut db, clock) = setup();
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeListing { price: 5.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::MakeListing { price: 20.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "2",
+            Operation::MakeListing { price: 10.into() },
+        );
+
+        assert_eq!(db.get(&"2".to_string()).unwrap().price, Some(10.into()));
+        assert_eq!(ids(&db, request("listing_price")), vec!["1", "2", "0"]);
+        assert_eq!(ids(&db, request("last_listing")), vec!["2", "1", "0"]);
+
+        // relisting moves the token in both indexes
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeListing { price: 50.into() },
+        );
+        assert_eq!(ids(&db, request("listing_price")), vec!["0", "1", "2"]);
+        assert_eq!(ids(&db, request("last_listing")), vec!["0", "2", "1"]);
+
+        index(&mut db, &clock, "1", Operation::CancelListing);
+        assert_eq!(db.get(&"1".to_string()).unwrap().price, None);
+        assert_eq!(ids(&db, request("listing_price")), vec!["0", "2"]);
+        assert_eq!(ids(&db, request("last_listing")), vec!["0", "2"]);
+    }
+
+    #[test]
+    fn make_and_cancel_offers() {
+        let (mut db, clock) = setup();
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeOffer {
+                buyer: principal(1),
+                price: 5.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::MakeOffer {
+                buyer: principal(1),
+                price: 8.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeOffer {
+                buyer: principal(2),
+                price: 12.into(),
+            },
+        );
+
+        let token = db.get(&"0".to_string()).unwrap();
+        assert_eq!(token.offers.len(), 2);
+        assert_eq!(token.best_offer, Some(12.into()));
+        assert_eq!(ids(&db, request("offer_price")), vec!["0", "1"]);
+        assert_eq!(ids(&db, request("last_offer")), vec!["0", "1"]);
+
+        // cancelling the best offer falls back to the next best offer
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::CancelOffer {
+                buyer: principal(2),
+            },
+        );
+        let token = db.get(&"0".to_string()).unwrap();
+        assert_eq!(token.offers.len(), 1);
+        assert_eq!(token.best_offer, Some(5.into()));
+        assert_eq!(ids(&db, request("offer_price")), vec!["1", "0"]);
+
+        // cancelling the only offer removes the token from the offer indexes
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::CancelOffer {
+                buyer: principal(1),
+            },
+        );
+        let token = db.get(&"0".to_string()).unwrap();
+        assert!(token.offers.is_empty());
+        assert_eq!(token.best_offer, None);
+        assert_eq!(ids(&db, request("offer_price")), vec!["1"]);
+        assert_eq!(ids(&db, request("last_offer")), vec!["1"]);
+    }
+
+    #[test]
+    fn offers_keep_the_best_and_replace_the_buyers_offer() {
+        let (mut db, clock) = setup();
+        let offer = |buyer: u8, price: u64| Operation::MakeOffer {
+            buyer: principal(buyer),
+            price: price.into(),
+        };
+        index(&mut db, &clock, "0", offer(1, 10));
+        index(&mut db, &clock, "1", offer(1, 8));
+
+        // a lower offer made later does not replace the best offer
+        index(&mut db, &clock, "0", offer(2, 3));
+        assert_eq!(
+            db.get(&"0".to_string()).unwrap().best_offer,
+            Some(10.into())
+        );
+        assert_eq!(ids(&db, request("offer_price")), vec!["0", "1"]);
+
+        // a buyer's new offer replaces their previous one, ie. lowering it
+        index(&mut db, &clock, "0", offer(1, 5));
+        let token = db.get(&"0".to_string()).unwrap();
+        assert_eq!(token.offers.len(), 2);
+        assert_eq!(token.best_offer, Some(5.into()));
+        assert_eq!(ids(&db, request("offer_price")), vec!["1", "0"]);
+
+        // cancelling it leaves the other buyer's offer
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::CancelOffer {
+                buyer: principal(1),
+            },
+        );
+        let token = db.get(&"0".to_string()).unwrap();
+        assert_eq!(token.offers.len(), 1);
+        assert_eq!(token.best_offer, Some(3.into()));
+    }
+
+    #[test]
+    fn direct_buy() {
+        let (mut db, clock) = setup();
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeListing { price: 10.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeOffer {
+                buyer: principal(1),
+                price: 5.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeOffer {
+                buyer: principal(2),
+                price: 7.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::DirectBuy {
+                buyer: principal(2),
+                price: 10.into(),
+            },
+        );
+
+        let token = db.get(&"0".to_string()).unwrap();
+        let sale = token.last_sale.as_ref().unwrap();
+        assert_eq!(sale.buyer, principal(2));
+        assert_eq!(sale.price, 10);
+        assert_eq!(sale.time, clock.now());
+        assert_eq!(token.price, None);
+        // the buyer's offer is fulfilled, other offers remain
+        assert_eq!(token.offers.len(), 1);
+        assert_eq!(token.best_offer, Some(5.into()));
+
+        assert!(ids(&db, request("listing_price")).is_empty());
+        assert_eq!(ids(&db, request("offer_price")), vec!["0"]);
+        assert_eq!(ids(&db, request("sale_price")), vec!["0"]);
+        assert_eq!(ids(&db, request("last_sale")), vec!["0"]);
+    }
+
+    #[test]
+    fn accept_offer() {
+        let (mut db, clock) = setup();
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::MakeOffer {
+                buyer: principal(1),
+                price: 5.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "2",
+            Operation::MakeOffer {
+                buyer: principal(1),
+                price: 9.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::AcceptOffer {
+                buyer: principal(1),
+                price: 5.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "2",
+            Operation::AcceptOffer {
+                buyer: principal(1),
+                price: 9.into(),
+            },
+        );
+
+        let token = db.get(&"1".to_string()).unwrap();
+        assert_eq!(token.last_sale.as_ref().unwrap().price, 5);
+        assert!(token.offers.is_empty());
+        assert_eq!(token.best_offer, None);
+
+        assert!(ids(&db, request("offer_price")).is_empty());
+        assert!(ids(&db, request("last_offer")).is_empty());
+        assert_eq!(ids(&db, request("sale_price")), vec!["2", "1"]);
+        assert_eq!(ids(&db, request("last_sale")), vec!["2", "1"]);
+    }
+
+    #[test]
+    fn already_applied_events_are_skipped() {
+        let (mut db, clock) = setup();
+        let offer = Event {
+            event_id: Some(42),
+            ..event(
+                "0",
+                Operation::MakeOffer {
+                    buyer: principal(1),
+                    price: 5.into(),
+                },
+            )
+        };
+
+        assert_eq!(
+            db.index_event(offer.clone(), &clock),
+            Ok(IndexResult::Applied)
+        );
+        assert_eq!(
+            db.index_event(offer, &clock),
+            Ok(IndexResult::AlreadyApplied)
+        );
+        assert_eq!(db.get(&"0".to_string()).unwrap().offers.len(), 1);
+    }
+
+    #[test]
+    fn rebuild_indexes_replays_the_log() {
+        let (mut db, clock) = setup();
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeListing { price: 10.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "3",
+            Operation::MakeListing { price: 4.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::MakeOffer {
+                buyer: principal(1),
+                price: 5.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::DirectBuy {
+                buyer: principal(2),
+                price: 10.into(),
+            },
+        );
+
+        let before: Vec<Vec<String>> = [
+            "listing_price",
+            "offer_price",
+            "sale_price",
+            "last_sale",
+            "all",
+        ]
+        .iter()
+        .map(|key| ids(&db, request(key)))
+        .collect();
+
+        // replay happens at a later time, but uses the logged times
+        clock.advance(1_000);
+        assert_eq!(db.rebuild_indexes(), 9);
+
+        let after: Vec<Vec<String>> = [
+            "listing_price",
+            "offer_price",
+            "sale_price",
+            "last_sale",
+            "all",
+        ]
+        .iter()
+        .map(|key| ids(&db, request(key)))
+        .collect();
+        assert_eq!(before, after);
+        assert_eq!(db.get_events(None, None).total, 9);
+    }
+
+    #[test]
+    fn event_time_is_preferred() {
+        let (mut db, clock) = setup();
+        let at = |token_id: &str, time: u64, operation: Operation| Event {
+            time: Some(time),
+            ..event(token_id, operation)
+        };
+
+        db.index_event(
+            at("0", 500, Operation::MakeListing { price: 1.into() }),
+            &clock,
+        )
+        .unwrap();
+        db.index_event(
+            at("1", 300, Operation::MakeListing { price: 1.into() }),
+            &clock,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get(&"0".to_string()).unwrap().last_listing,
+            Some(500.into())
+        );
+        assert_eq!(ids(&db, request("last_listing")), vec!["0", "1"]);
+
+        // an older listing received late does not replace the listing, or move the token back
+        db.index_event(
+            at("0", 100, Operation::MakeListing { price: 2.into() }),
+            &clock,
+        )
+        .unwrap();
+        let token = db.get(&"0".to_string()).unwrap();
+        assert_eq!(token.price, Some(1.into()));
+        assert_eq!(token.last_listing, Some(500.into()));
+        assert_eq!(ids(&db, request("last_listing")), vec!["0", "1"]);
+        assert_eq!(
+            db.sort_index["listing_price"].value("0"),
+            Some(&Nat::from(1))
+        );
+        // but it is still part of the token's history
+        assert_eq!(db.get_token_history(&"0".to_string(), None, None).total, 2);
+
+        // nor does an older cancellation or sale remove it
+        db.index_event(at("0", 200, Operation::CancelListing), &clock)
+            .unwrap();
+        db.index_event(
+            at(
+                "0",
+                300,
+                Operation::DirectBuy {
+                    buyer: principal(2),
+                    price: 3.into(),
+                },
+            ),
+            &clock,
+        )
+        .unwrap();
+        let token = db.get(&"0".to_string()).unwrap();
+        assert_eq!(token.price, Some(1.into()));
+        assert_eq!(token.last_sale.as_ref().unwrap().price, 3u64);
+        assert_eq!(ids(&db, request("listing_price")), vec!["1", "0"]);
+
+        // an older sale received late does not replace the last sale
+        let sale = |time: u64, price: u64| {
+            at(
+                "2",
+                time,
+                Operation::DirectBuy {
+                    buyer: principal(1),
+                    price: price.into(),
+                },
+            )
+        };
+        db.index_event(sale(400, 10), &clock).unwrap();
+        db.index_event(sale(200, 99), &clock).unwrap();
+        let last_sale = db.get(&"2".to_string()).unwrap().last_sale.clone().unwrap();
+        assert_eq!((last_sale.time, last_sale.price), (400.into(), 10.into()));
+        assert_eq!(db.get_events(Some(11), None).data[0].time, 200);
+
+        // an older cancel or offer received late does not replace the buyer's newer offer
+        let offer = |time: u64, price: u64| {
+            at(
+                "3",
+                time,
+                Operation::MakeOffer {
+                    buyer: principal(1),
+                    price: price.into(),
+                },
+            )
+        };
+        db.index_event(offer(100, 5), &clock).unwrap();
+        db.index_event(offer(300, 6), &clock).unwrap();
+        let cancel = Operation::CancelOffer {
+            buyer: principal(1),
+        };
+        db.index_event(at("3", 200, cancel.clone()), &clock)
+            .unwrap();
+        db.index_event(offer(250, 99), &clock).unwrap();
+        let token = db.get(&"3".to_string()).unwrap();
+        assert_eq!(token.offers.len(), 1);
+        assert_eq!(token.best_offer, Some(6.into()));
+        assert_eq!(ids(&db, request("offer_price")), vec!["3"]);
+
+        // a newer cancel still removes it
+        db.index_event(at("3", 400, cancel), &clock).unwrap();
+        assert!(db.get(&"3".to_string()).unwrap().offers.is_empty());
+        assert!(ids(&db, request("offer_price")).is_empty());
+    }
+
+    #[test]
+    fn token_history() {
+        let (mut db, clock) = setup();
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeListing { price: 10.into() },
+        );
+        index(&mut db, &clock, "0", Operation::CancelListing);
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeOffer {
+                buyer: principal(1),
+                price: 5.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::AcceptOffer {
+                buyer: principal(1),
+                price: 5.into(),
+            },
+        );
+
+        let history = db.get_token_history(&"0".to_string(), None, Some(2));
+        assert_eq!(history.total, 3);
+        assert_eq!(history.next, Some(2));
+        assert!(matches!(history.data[0].activity, Activity::Sale { .. }));
+        assert!(matches!(history.data[1].activity, Activity::Offer { .. }));
+
+        let history = db.get_token_history(&"0".to_string(), history.next, Some(2));
+        assert_eq!(history.next, None);
+        assert_eq!(
+            history.data[0].activity,
+            Activity::Listing {
+                fungible: Principal::management_canister(),
+                price: 10.into()
+            }
+        );
+
+        // history is bounded, dropping the oldest entries
+        for price in 0..TOKEN_HISTORY_LIMIT as u64 {
+            index(
+                &mut db,
+                &clock,
+                "1",
+                Operation::MakeListing {
+                    price: price.into(),
+                },
+            );
+        }
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::MakeListing { price: 1000.into() },
+        );
+        let history = db.get_token_history(&"1".to_string(), Some(TOKEN_HISTORY_LIMIT - 1), None);
+        assert_eq!(history.total, TOKEN_HISTORY_LIMIT);
+        assert_eq!(
+            history.data[0].activity,
+            Activity::Listing {
+                fungible: Principal::management_canister(),
+                price: 1.into()
+            }
+        );
+    }
+
+    #[test]
+    fn collection_stats() {
+        let (mut db, clock) = setup();
+        let stats = db.get_stats(&clock);
+        assert_eq!(stats.tokens, 5);
+        assert_eq!(stats.listed, 0);
+        assert!(stats.fungibles.is_empty());
+
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeListing { price: 30.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::MakeListing { price: 20.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "2",
+            Operation::MakeListing { price: 10.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "2",
+            Operation::DirectBuy {
+                buyer: principal(1),
+                price: 10.into(),
+            },
+        );
+        let stats = db.get_stats(&clock);
+        assert_eq!(stats.listed, 2);
+        assert_eq!(stats.fungibles.len(), 1);
+        assert_eq!(
+            stats.fungibles[0].fungible,
+            Principal::management_canister()
+        );
+        assert_eq!(stats.fungibles[0].listed, 2);
+        assert_eq!(stats.fungibles[0].floor, Some(20.into()));
+
+        // a sale 2 days later, and another 8 days later
+        clock.advance(2 * DAY);
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::DirectBuy {
+                buyer: principal(1),
+                price: 20.into(),
+            },
+        );
+        clock.advance(6 * DAY);
+        index(
+            &mut db,
+            &clock,
+            "3",
+            Operation::AcceptOffer {
+                buyer: principal(2),
+                price: 5.into(),
+            },
+        );
+
+        // a sale in another fungible is not added to the volume of the first
+        let other = principal(10);
+        clock.advance(1);
+        let sale = Operation::DirectBuy {
+            buyer: principal(1),
+            price: 1000.into(),
+        };
+        db.index_event(
+            Event {
+                fungible_id: Some(other),
+                ..event("4", sale)
+            },
+            &clock,
+        )
+        .unwrap();
+
+        let stats = db.get_stats(&clock);
+        let volume = |sales: u64, volume: u64| Volume {
+            sales,
+            volume: volume.into(),
+        };
+        assert_eq!(stats.fungibles.len(), 2);
+        let base = &stats.fungibles[0];
+        assert_eq!(base.fungible, Principal::management_canister());
+        assert_eq!(base.floor, Some(30.into()));
+        assert_eq!(base.day, volume(1, 5));
+        assert_eq!(base.week, volume(2, 25));
+        assert_eq!(base.all_time, volume(3, 35));
+        let other_stats = &stats.fungibles[1];
+        assert_eq!(other_stats.fungible, other);
+        assert_eq!(other_stats.listed, 0);
+        assert_eq!(other_stats.floor, None);
+        assert_eq!(other_stats.all_time, volume(1, 1000));
+
+        // stats are rebuilt from the log
+        db.rebuild_indexes();
+        assert_eq!(db.get_stats(&clock).fungibles[0].week, volume(2, 25));
+    }
+
+    #[test]
+    fn timeseries() {
+        let (mut db, clock) = setup();
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeListing { price: 30.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::MakeListing { price: 20.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::DirectBuy {
+                buyer: principal(1),
+                price: 20.into(),
+            },
+        );
+        clock.advance(HOUR);
+        index(
+            &mut db,
+            &clock,
+            "2",
+            Operation::DirectBuy {
+                buyer: principal(1),
+                price: 10.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "3",
+            Operation::DirectBuy {
+                buyer: principal(1),
+                price: 15.into(),
+            },
+        );
+
+        let fungible = Principal::management_canister();
+        let hourly = db.get_timeseries(&fungible, Interval::Hour, 0, clock.now());
+        assert_eq!(hourly.len(), 2);
+        assert_eq!(hourly[0].start, 0);
+        assert_eq!(hourly[0].floor, Some(20.into()));
+        assert_eq!(hourly[0].floor_close, Some(30.into()));
+        assert_eq!(hourly[1].start, HOUR);
+        assert_eq!(hourly[1].sales, 2);
+        assert_eq!(hourly[1].min_price, Some(10.into()));
+        assert_eq!(hourly[1].max_price, Some(15.into()));
+        assert_eq!(hourly[1].avg_price, Some(12.into()));
+        // sales of unlisted tokens leave the floor as is
+        assert!(!hourly[1].floor_updated);
+        assert_eq!(hourly[1].floor, None);
+
+        let daily = db.get_timeseries(&fungible, Interval::Day, 0, clock.now());
+        assert_eq!(daily.len(), 1);
+        assert_eq!(daily[0].sales, 3);
+        assert_eq!(daily[0].volume, Nat::from(45));
+        assert_eq!(daily[0].avg_price, Some(15.into()));
+        assert_eq!(daily[0].floor, Some(20.into()));
+        assert_eq!(daily[0].floor_close, Some(30.into()));
+
+        assert_eq!(
+            db.get_timeseries(&fungible, Interval::Hour, HOUR, clock.now())
+                .len(),
+            1
+        );
+        assert!(db
+            .get_timeseries(&fungible, Interval::Hour, clock.now(), 0)
+            .is_empty());
+        assert!(db
+            .get_timeseries(&principal(10), Interval::Hour, 0, clock.now())
+            .is_empty());
+
+        // the floor closes empty once the last listing ends
+        clock.advance(HOUR);
+        index(&mut db, &clock, "0", Operation::CancelListing);
+        let hourly = db.get_timeseries(&fungible, Interval::Hour, 2 * HOUR, clock.now());
+        assert_eq!(hourly.len(), 1);
+        assert!(hourly[0].floor_updated);
+        assert_eq!(hourly[0].floor, None);
+        assert_eq!(hourly[0].floor_close, None);
+    }
+
+    #[test]
+    fn late_listings_keep_past_floors() {
+        let (mut db, clock) = setup();
+        let fungible = Principal::management_canister();
+        let at = |token_id: &str, hour: u64, operation: Operation| Event {
+            time: Some(hour * HOUR),
+            ..event(token_id, operation)
+        };
+
+        db.index_event(
+            at("0", 10, Operation::MakeListing { price: 5.into() }),
+            &clock,
+        )
+        .unwrap();
+        // an older listing of the same token is only history
+        db.index_event(
+            at("0", 1, Operation::MakeListing { price: 8.into() }),
+            &clock,
+        )
+        .unwrap();
+        let hourly = db.get_timeseries(&fungible, Interval::Hour, 0, 20 * HOUR);
+        assert_eq!(hourly.len(), 1);
+        assert_eq!(hourly[0].start, 10 * HOUR);
+        assert_eq!(hourly[0].floor_close, Some(5.into()));
+
+        // a late listing of another token moves the current floor, not a past candle
+        db.index_event(
+            at("1", 2, Operation::MakeListing { price: 3.into() }),
+            &clock,
+        )
+        .unwrap();
+        let hourly = db.get_timeseries(&fungible, Interval::Hour, 0, 20 * HOUR);
+        assert_eq!(hourly.len(), 1);
+        assert_eq!(hourly[0].start, 10 * HOUR);
+        assert_eq!(hourly[0].floor, Some(3.into()));
+        assert_eq!(hourly[0].floor_close, Some(3.into()));
+    }
+
+    #[test]
+    fn get_tokens() {
+        let (db, _) = setup();
+        let tokens = db
+            .get_tokens(vec!["3".to_string(), "9".to_string(), "0".to_string()])
+            .unwrap();
+        assert_eq!(tokens[0].as_ref().unwrap().id, "3");
+        assert_eq!(tokens[1].as_ref().unwrap_err(), &"Token not indexed");
+        assert_eq!(tokens[2].as_ref().unwrap().id, "0");
+
+        let too_many = (0..=PAGE_SIZE_LIMIT).map(|i| i.to_string()).collect();
+        assert!(db.get_tokens(too_many).is_err());
+    }
+
+    #[test]
+    fn principal_indexes() {
+        let (mut db, clock) = setup();
+        let (alice, bob) = (principal(1), principal(2));
+        let sell = |token_id: &str, price: u64| Event {
+            seller: Some(alice),
+            ..event(
+                token_id,
+                Operation::MakeListing {
+                    price: price.into(),
+                },
+            )
+        };
+        let ids = |tokens: TokensResponse| -> Vec<String> {
+            tokens.data.into_iter().map(|token| token.id).collect()
+        };
+
+        db.index_event(sell("0", 10), &clock).unwrap();
+        db.index_event(sell("1", 20), &clock).unwrap();
+        db.index_event(sell("2", 30), &clock).unwrap();
+        index(&mut db, &clock, "2", Operation::CancelListing);
+        index(
+            &mut db,
+            &clock,
+            "3",
+            Operation::MakeOffer {
+                buyer: bob,
+                price: 5.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "4",
+            Operation::MakeOffer {
+                buyer: bob,
+                price: 5.into(),
+            },
+        );
+        index(&mut db, &clock, "4", Operation::CancelOffer { buyer: bob });
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeOffer {
+                buyer: bob,
+                price: 5.into(),
+            },
+        );
+        assert_eq!(ids(db.listings_of(&alice, None, None)), vec!["0", "1"]);
+        assert_eq!(ids(db.offers_of(&bob, None, None)), vec!["0", "3"]);
+
+        // the listing seller is the seller of a sale without one
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::AcceptOffer {
+                buyer: bob,
+                price: 5.into(),
+            },
+        );
+        clock.advance(1);
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::DirectBuy {
+                buyer: bob,
+                price: 20.into(),
+            },
+        );
+        assert!(db.listings_of(&alice, None, None).data.is_empty());
+        assert_eq!(ids(db.offers_of(&bob, None, None)), vec!["3"]);
+
+        let purchases = db.purchases_of(&bob, None, Some(1));
+        assert_eq!(purchases.total, 2);
+        assert_eq!(purchases.next, Some(1));
+        assert_eq!(purchases.data[0].token_id, "1");
+        let sales = db.sales_of(&alice, Some(1), None);
+        assert_eq!(sales.next, None);
+        assert_eq!(sales.data[0].token_id, "0");
+        assert_eq!(sales.data[0].buyer, bob);
+
+        // a listing without a seller is listed by the owner
+        index(
+            &mut db,
+            &clock,
+            "1",
+            Operation::MakeListing { price: 30.into() },
+        );
+        assert_eq!(ids(db.listings_of(&bob, None, None)), vec!["1"]);
+    }
+
+    #[test]
+    fn ownership() {
+        let (mut db, clock) = setup();
+        let (alice, bob) = (principal(1), principal(2));
+        let mint_to = |token_id: &str, owner: Principal| {
+            event(
+                token_id,
+                Operation::Mint {
+                    traits: Some(HashMap::from([("base".to_string(), text("Gold"))])),
+                    to: Some(owner),
+                },
+            )
+        };
+        let tokens_of = |db: &Database, owner: &Principal, request: QueryRequest| -> Vec<String> {
+            let response = db.tokens_of(owner, request);
+            assert!(response.error.is_none(), "{:?}", response.error);
+            response.data.into_iter().map(|token| token.id).collect()
+        };
+
+        db.index_event(mint_to("5", alice), &clock).unwrap();
+        db.index_event(mint_to("6", alice), &clock).unwrap();
+        db.index_event(mint_to("7", alice), &clock).unwrap();
+        index(
+            &mut db,
+            &clock,
+            "5",
+            Operation::MakeListing { price: 10.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "6",
+            Operation::MakeListing { price: 20.into() },
+        );
+        assert_eq!(
+            tokens_of(&db, &alice, request("listing_price")),
+            vec!["6", "5"]
+        );
+
+        // a transfer cancels the listing
+        index(&mut db, &clock, "6", Operation::Transfer { to: bob });
+        assert_eq!(db.get(&"6".to_string()).unwrap().owner, Some(bob));
+        assert_eq!(db.get(&"6".to_string()).unwrap().price, None);
+        assert_eq!(ids(&db, request("listing_price")), vec!["5"]);
+
+        // a sale moves the token to the buyer
+        index(
+            &mut db,
+            &clock,
+            "5",
+            Operation::DirectBuy {
+                buyer: bob,
+                price: 10.into(),
+            },
+        );
+        assert_eq!(tokens_of(&db, &alice, request("all")), vec!["7"]);
+        assert_eq!(tokens_of(&db, &bob, request("all")), vec!["5", "6"]);
+        assert_eq!(db.sales_of(&alice, None, None).total, 1);
+        assert_eq!(db.get_stats(&clock).owners, 2);
+
+        // filters still apply, and unknown owners have no tokens
+        let gold = QueryRequest {
+            traits: Some(vec![("base".to_string(), text("Gold"))]),
+            ..request("all")
+        };
+        assert_eq!(tokens_of(&db, &bob, gold), vec!["5", "6"]);
+        assert!(tokens_of(&db, &principal(3), request("all")).is_empty());
+    }
+
+    #[test]
+    fn late_transfers_keep_the_current_owner() {
+        let (mut db, clock) = setup();
+        let (alice, bob, carol) = (principal(1), principal(2), principal(3));
+        let at = |time: u64, operation: Operation| Event {
+            time: Some(time),
+            ..event("0", operation)
+        };
+
+        db.index_event(at(100, Operation::Transfer { to: alice }), &clock)
+            .unwrap();
+        db.index_event(at(300, Operation::Transfer { to: bob }), &clock)
+            .unwrap();
+        db.index_event(at(400, Operation::MakeListing { price: 5.into() }), &clock)
+            .unwrap();
+
+        // a transfer and a sale from before the current owner received it are backfilled
+        db.index_event(at(200, Operation::Transfer { to: carol }), &clock)
+            .unwrap();
+        let sale = Operation::DirectBuy {
+            buyer: carol,
+            price: 1.into(),
+        };
+        db.index_event(at(250, sale), &clock).unwrap();
+
+        let token = db.get(&"0".to_string()).unwrap();
+        assert_eq!(token.owner, Some(bob));
+        assert_eq!(token.last_transfer, Some(300.into()));
+        assert_eq!(token.price, Some(5.into()));
+        assert!(db.tokens_of(&carol, request("all")).data.is_empty());
+        assert_eq!(db.tokens_of(&bob, request("all")).total, 1);
+
+        // a newer transfer cancels the listing made before it
+        db.index_event(at(500, Operation::Transfer { to: carol }), &clock)
+            .unwrap();
+        let token = db.get(&"0".to_string()).unwrap();
+        assert_eq!(token.owner, Some(carol));
+        assert_eq!(token.price, None);
+    }
+
+    #[test]
+    fn burn_purges_every_index() {
+        let (mut db, clock) = setup();
+        let bob = principal(2);
+        db.index_event(
+            Event {
+                seller: Some(principal(1)),
+                ..event("4", Operation::MakeListing { price: 10.into() })
+            },
+            &clock,
+        )
+        .unwrap();
+        index(
+            &mut db,
+            &clock,
+            "4",
+            Operation::MakeOffer {
+                buyer: bob,
+                price: 5.into(),
+            },
+        );
+        index(
+            &mut db,
+            &clock,
+            "3",
+            Operation::DirectBuy {
+                buyer: bob,
+                price: 5.into(),
+            },
+        );
+        index(&mut db, &clock, "3", Operation::Burn);
+        index(&mut db, &clock, "4", Operation::Burn);
+
+        assert!(db.get(&"4".to_string()).is_none());
+        assert_eq!(ids(&db, request("all")), vec!["2", "1", "0"]);
+        for key in ["listing_price", "offer_price", "sale_price", "last_sale"] {
+            assert!(ids(&db, request(key)).is_empty(), "{}", key);
+        }
+        assert_eq!(db.get_token_history(&"4".to_string(), None, None).total, 0);
+        assert!(db.offers_of(&bob, None, None).data.is_empty());
+        assert!(db.listings_of(&principal(1), None, None).data.is_empty());
+        assert!(db.tokens_of(&bob, request("all")).data.is_empty());
+
+        // empty trait buckets are deleted
+        let facets = db.facets(FacetRequest {
+            sort_key: None,
+            traits: None,
+            filter: None,
+            ranges: None,
+        });
+        let base = facets.facets.iter().find(|f| f.key == "base").unwrap();
+        assert!(base.values.iter().all(|v| v.value != text("Bronze")));
+        assert!(db.numeric_traits["level"]
+            .range(Int::from(3)..)
+            .next()
+            .is_none());
+
+        // burned tokens can't be traded, but can be minted again
+        let result = db.index_event(event("4", Operation::CancelListing), &clock);
+        assert_eq!(result, Err("Token not indexed"));
+        mint(&mut db, &clock, "4", &[]);
+        assert_eq!(ids(&db, request("all")), vec!["4", "2", "1", "0"]);
+    }
+
+    #[test]
+    fn update_metadata_reindexes_traits() {
+        let (mut db, clock) = setup();
+        let traits = |traits: &[(&str, GenericValue)]| {
+            Some(
+                traits
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect(),
+            )
+        };
+        let trait_ids = |db: &Database, key: &str, value: GenericValue| {
+            let mut ids: Vec<String> = db
+                .eval_filter(&TraitFilter::Trait(key.to_string(), value))
+                .into_iter()
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        // token 4 evolves from Bronze to Gold, changes level and gains a trait
+        index(
+            &mut db,
+            &clock,
+            "4",
+            Operation::UpdateMetadata {
+                traits: traits(&[
+                    ("base", text("Gold")),
+                    ("level", GenericValue::Nat8Content(9)),
+                    ("aura", text("Glowing")),
+                ]),
+            },
+        );
+        assert_eq!(trait_ids(&db, "base", text("Gold")), vec!["0", "1", "4"]);
+        assert!(!db.trait_maps["base"].contains_key(&text("Bronze")));
+        assert!(!db.numeric_traits["level"].contains_key(&Int::from(4)));
+        assert_eq!(trait_ids(&db, "aura", text("Glowing")), vec!["4"]);
+
+        // removed traits are dropped, and empty keys deleted
+        index(
+            &mut db,
+            &clock,
+            "4",
+            Operation::UpdateMetadata {
+                traits: traits(&[("base", text("Gold"))]),
+            },
+        );
+        assert!(!db.trait_maps.contains_key("aura"));
+        assert!(!db.numeric_traits["level"].contains_key(&Int::from(9)));
+        assert_eq!(trait_ids(&db, "base", text("Gold")), vec!["0", "1", "4"]);
+
+        // minting again replaces the traits as well
+        mint(&mut db, &clock, "0", &[("base", text("Silver"))]);
+        assert_eq!(trait_ids(&db, "base", text("Gold")), vec!["1", "4"]);
+        assert!(!db.numeric_traits["level"].contains_key(&Int::from(0)));
+    }
+
+    #[test]
+    fn fungible_price_indexes() {
+        let (mut db, clock) = setup();
+        let (wicp, xtc) = (principal(10), principal(11));
+        let paid = |token_id: &str, fungible: Principal, operation: Operation| Event {
+            fungible_id: Some(fungible),
+            ..event(token_id, operation)
+        };
+        let within = |sort_key: &str, fungible: Principal| QueryRequest {
+            fungible: Some(fungible),
+            ..request(sort_key)
+        };
+        let buyer = principal(1);
+
+        for (token_id, fungible, price) in [("0", wicp, 10), ("1", xtc, 5), ("2", wicp, 20)] {
+            let listing = Operation::MakeListing {
+                price: price.into(),
+            };
+            db.index_event(paid(token_id, fungible, listing), &clock)
+                .unwrap();
+        }
+        assert_eq!(ids(&db, request("listing_price")), vec!["2", "0", "1"]);
+        assert_eq!(ids(&db, within("listing_price", wicp)), vec!["2", "0"]);
+        assert_eq!(ids(&db, within("listing_price", xtc)), vec!["1"]);
+
+        // relisting in another fungible moves the token between partitions
+        let listing = Operation::MakeListing { price: 1.into() };
+        db.index_event(paid("2", xtc, listing), &clock).unwrap();
+        assert_eq!(ids(&db, within("listing_price", wicp)), vec!["0"]);
+        assert_eq!(ids(&db, within("listing_price", xtc)), vec!["1", "2"]);
+
+        // offers are sorted by the best offer in each fungible
+        let offer = |buyer: Principal, price: u64| Operation::MakeOffer {
+            buyer,
+            price: price.into(),
+        };
+        db.index_event(paid("3", wicp, offer(buyer, 30)), &clock)
+            .unwrap();
+        db.index_event(paid("4", xtc, offer(buyer, 40)), &clock)
+            .unwrap();
+        db.index_event(paid("4", wicp, offer(principal(2), 3)), &clock)
+            .unwrap();
+        assert_eq!(ids(&db, within("offer_price", wicp)), vec!["3", "4"]);
+        assert_eq!(ids(&db, within("offer_price", xtc)), vec!["4"]);
+
+        // replacing an offer in another fungible moves it between partitions
+        db.index_event(paid("4", wicp, offer(buyer, 4)), &clock)
+            .unwrap();
+        assert!(ids(&db, within("offer_price", xtc)).is_empty());
+        assert_eq!(ids(&db, within("offer_price", wicp)), vec!["3", "4"]);
+
+        // sales remove the listing and index the sale in its fungible
+        let sale = Operation::DirectBuy {
+            buyer,
+            price: 10.into(),
+        };
+        db.index_event(paid("0", wicp, sale), &clock).unwrap();
+        assert!(ids(&db, within("listing_price", wicp)).is_empty());
+        assert_eq!(ids(&db, within("sale_price", wicp)), vec!["0"]);
+
+        // price ranges apply within the fungible
+        let ranged = QueryRequest {
+            ranges: Some(vec![RangeFilter {
+                sort_key: "offer_price".to_string(),
+                min: Some(10.into()),
+                max: None,
+            }]),
+            ..within("offer_price", wicp)
+        };
+        assert_eq!(ids(&db, ranged), vec!["3"]);
+
+        let response = db.query(within("last_sale", wicp));
+        assert!(response.error.is_some());
+        let response = db.query(within("listing_price", principal(12)));
+        assert_eq!(response.error, Some("Fungible not found".to_string()));
+    }
+
+    #[test]
+    fn normalized_price_indexes() {
+        let (mut db, clock) = setup();
+        let (wicp, xtc) = (principal(10), principal(11));
+        let rate = |rate: u64, decimals: u8| {
+            Some(FungibleRate {
+                rate: rate.into(),
+                decimals,
+            })
+        };
+        let list = |db: &mut Database, token_id: &str, fungible: Principal, price: u64| {
+            let event = Event {
+                fungible_id: Some(fungible),
+                ..event(
+                    token_id,
+                    Operation::MakeListing {
+                        price: price.into(),
+                    },
+                )
+            };
+            db.index_event(event, &clock).unwrap();
+        };
+
+        // 2 WICP and 3 XTC, with 8 and 12 decimals
+        list(&mut db, "0", wicp, 200_000_000);
+        list(&mut db, "1", xtc, 3_000_000_000_000);
+        list(&mut db, "2", principal(12), 1);
+        assert!(ids(&db, request("normalized_listing_price")).is_empty());
+
+        assert_eq!(db.set_rate(wicp, rate(10, 8)), 1);
+        assert_eq!(db.set_rate(xtc, rate(5, 12)), 1);
+        assert_eq!(
+            ids(&db, request("normalized_listing_price")),
+            vec!["0", "1"]
+        );
+        assert_eq!(
+            db.sort_index["normalized_listing_price"].value("0"),
+            Some(&Nat::from(20))
+        );
+
+        // rate changes re-sort the tokens, and tokens without a rate are left out
+        db.set_rate(xtc, rate(8, 12));
+        assert_eq!(
+            ids(&db, request("normalized_listing_price")),
+            vec!["1", "0"]
+        );
+        db.set_rate(wicp, None);
+        assert_eq!(ids(&db, request("normalized_listing_price")), vec!["1"]);
+
+        // offers and sales are normalized as they are indexed, and rates survive a rebuild
+        let buyer = principal(1);
+        let offer = Event {
+            fungible_id: Some(xtc),
+            ..event(
+                "3",
+                Operation::MakeOffer {
+                    buyer,
+                    price: 1_000_000_000_000u64.into(),
+                },
+            )
+        };
+        db.index_event(offer, &clock).unwrap();
+        let sale = Event {
+            fungible_id: Some(xtc),
+            ..event(
+                "1",
+                Operation::DirectBuy {
+                    buyer,
+                    price: 3_000_000_000_000u64.into(),
+                },
+            )
+        };
+        db.index_event(sale, &clock).unwrap();
+        db.rebuild_indexes();
+        assert_eq!(db.get_rates(), vec![(xtc, rate(8, 12).unwrap())]);
+        assert!(ids(&db, request("normalized_listing_price")).is_empty());
+        assert_eq!(ids(&db, request("normalized_offer_price")), vec!["3"]);
+        assert_eq!(
+            db.sort_index["normalized_sale_price"].value("1"),
+            Some(&Nat::from(24))
+        );
+    }
+
+    #[test]
+    fn trait_filters() {
+        let (db, _) = setup();
+        let gold = TraitFilter::Trait("base".to_string(), text("Gold"));
+        let silver = TraitFilter::Trait("base".to_string(), text("Silver"));
+        let level = TraitFilter::Trait("level".to_string(), GenericValue::Nat8Content(1));
+
+        // legacy traits match any of the values
+        let mut query = request("all");
+        query.traits = Some(vec![
+            ("base".to_string(), text("Gold")),
+            ("base".to_string(), text("Bronze")),
+        ]);
+        assert_eq!(ids(&db, query), vec!["4", "1", "0"]);
+
+        let mut query = request("all");
+        query.filter = Some(TraitFilter::And(vec![
+            TraitFilter::Or(vec![gold.clone(), silver]),
+            TraitFilter::Not(Box::new(level)),
+        ]));
+        assert_eq!(ids(&db, query.clone()), vec!["3", "2", "0"]);
+        assert_eq!(db.query(query).total, 3);
+
+        let mut query = request("all");
+        query.filter = Some(TraitFilter::TraitRange {
+            key: "level".to_string(),
+            min: Some(Int::from(1)),
+            max: Some(Int::from(3)),
+        });
+        query.traits = Some(vec![("base".to_string(), text("Silver"))]);
+        assert_eq!(ids(&db, query), vec!["3", "2"]);
+    }
+
+    #[test]
+    fn mixed_width_trait_ranges() {
+        let (mut db, clock) = setup();
+        // numeric variants of different widths and signedness under one key
+        for (token_id, weight) in [
+            ("5", GenericValue::Nat8Content(200)),
+            ("6", GenericValue::Int64Content(-5)),
+            ("7", GenericValue::NatContent(1_000u64.into())),
+            ("8", GenericValue::Int64Content(200)),
+        ] {
+            mint(&mut db, &clock, token_id, &[("weight", weight)]);
+        }
+
+        // buckets are ordered by value, and equal values share a bucket
+        let buckets: Vec<(Int, Vec<String>)> = db.numeric_traits["weight"]
+            .iter()
+            .map(|(value, ids)| (value.clone(), ids.clone()))
+            .collect();
+        assert_eq!(
+            buckets,
+            vec![
+                (Int::from(-5), vec!["6".to_string()]),
+                (Int::from(200), vec!["5".to_string(), "8".to_string()]),
+                (Int::from(1_000), vec!["7".to_string()]),
+            ]
+        );
+
+        let range = |min: Option<i64>, max: Option<i64>| {
+            let mut query = request("all");
+            query.filter = Some(TraitFilter::TraitRange {
+                key: "weight".to_string(),
+                min: min.map(Int::from),
+                max: max.map(Int::from),
+            });
+            ids(&db, query)
+        };
+        assert_eq!(range(Some(-10), Some(200)), vec!["8", "6", "5"]);
+        assert_eq!(range(Some(0), None), vec!["8", "7", "5"]);
+        assert_eq!(range(None, Some(-1)), vec!["6"]);
+        assert_eq!(range(Some(201), Some(1_000)), vec!["7"]);
+    }
+
+    #[test]
+    fn range_filters() {
+        let (mut db, clock) = setup();
+        for (id, price) in [("0", 5), ("1", 15), ("2", 10), ("3", 20)] {
+            index(
+                &mut db,
+                &clock,
+                id,
+                Operation::MakeListing {
+                    price: price.into(),
+                },
+            );
+        }
+
+        let mut query = request("all");
+        query.ranges = Some(vec![RangeFilter {
+            sort_key: "listing_price".to_string(),
+            min: Some(10.into()),
+            max: Some(15.into()),
+        }]);
+        assert_eq!(ids(&db, query.clone()), vec!["2", "1"]);
+
+        query.ranges.as_mut().unwrap()[0].sort_key = "unknown".to_string();
+        assert!(db.query(query).error.is_some());
+    }
+
+    #[test]
+    fn cursor_survives_inserts() {
+        let (mut db, clock) = setup();
+        let mut query = request("all");
+        query.count = Some(2);
+
+        let page = db.query(query.clone());
+        assert_eq!(page.total, 5);
+        assert_eq!(page.data.len(), 2);
+
+        // a token moving to the top of the index does not shift the next page
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeListing { price: 1.into() },
+        );
+        query.cursor = page.cursor;
+        assert_eq!(ids(&db, query.clone()), vec!["2", "1"]);
+
+        // cursor pages seek from the cursor, and the last page has no cursor
+        let page = db.query(query.clone());
+        assert_eq!(page.last_index, None);
+        assert_eq!(page.cursor, None);
+
+        // ascending, with a filter
+        let mut query = request("all");
+        query.count = Some(1);
+        query.reverse = Some(true);
+        query.traits = Some(vec![("base".to_string(), text("Silver"))]);
+        let page = db.query(query.clone());
+        assert_eq!(page.total, 2);
+        assert_eq!(page.data[0].id, "2");
+        query.cursor = page.cursor;
+        let page = db.query(query.clone());
+        assert_eq!(page.total, 2);
+        assert_eq!(page.data[0].id, "3");
+        query.cursor = page.cursor;
+        let page = db.query(query.clone());
+        assert!(page.data.is_empty());
+        assert_eq!(page.cursor, None);
+
+        query.cursor = Some("invalid".to_string());
+        assert!(db.query(query).error.is_some());
+    }
+
+    #[test]
+    fn facet_counts() {
+        let (mut db, clock) = setup();
+        index(
+            &mut db,
+            &clock,
+            "0",
+            Operation::MakeListing { price: 1.into() },
+        );
+        index(
+            &mut db,
+            &clock,
+            "2",
+            Operation::MakeListing { price: 1.into() },
+        );
 
-        Ok(())
+        let response = db.facets(FacetRequest {
+            sort_key: Some("listing_price".to_string()),
+            traits: None,
+            filter: None,
+            ranges: None,
+        });
+        let base = response.facets.iter().find(|f| f.key == "base").unwrap();
+        let gold = base
+            .values
+            .iter()
+            .find(|v| v.value == text("Gold"))
+            .unwrap();
+        assert_eq!((gold.total, gold.count), (2, 1));
+        let bronze = base
+            .values
+            .iter()
+            .find(|v| v.value == text("Bronze"))
+            .unwrap();
+        assert_eq!((bronze.total, bronze.count), (1, 0));
     }
 }
@@ -0,0 +1,181 @@
+use candid::{CandidType, Deserialize, Nat};
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+
+/// Sort index of token ids, ordered by (sort value, token id).
+///
+/// A reverse map of token id to sort value allows updating or removing a token's entry in
+/// O(log n), without scanning the index.
+#[derive(CandidType, Clone, Deserialize, Default)]
+pub struct SortIndex {
+    // (sort value, token id), in ascending order
+    sorted: BTreeSet<(Nat, String)>,
+    // token id: sort value
+    values: HashMap<String, Nat>,
+}
+
+impl SortIndex {
+    pub fn len(&self) -> usize {
+        self.sorted.len()
+    }
+
+    pub fn contains(&self, token_id: &str) -> bool {
+        self.values.contains_key(token_id)
+    }
+
+    /// sort value of a token, if it is in the index
+    pub fn value(&self, token_id: &str) -> Option<&Nat> {
+        self.values.get(token_id)
+    }
+
+    /// insert a token, or move it if it is already in the index
+    pub fn insert(&mut self, token_id: String, value: Nat) {
+        if let Some(old) = self.values.insert(token_id.clone(), value.clone()) {
+            self.sorted.remove(&(old, token_id.clone()));
+        }
+        self.sorted.insert((value, token_id));
+    }
+
+    /// remove a token, returning true if it was in the index
+    pub fn remove(&mut self, token_id: &str) -> bool {
+        match self.values.remove(token_id) {
+            Some(value) => {
+                self.sorted.remove(&(value, token_id.to_string()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// token ids in ascending order
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &String> + Clone {
+        self.sorted.iter().map(|(_, id)| id)
+    }
+
+    /// token ids with a sort value within the inclusive bounds, in ascending order
+    pub fn range(
+        &self,
+        min: Option<&Nat>,
+        max: Option<&Nat>,
+    ) -> impl DoubleEndedIterator<Item = &String> + Clone {
+        // (value, "") is the first possible entry of a value, so the upper bound is the first
+        // possible entry of the next value.
+        let lower = min.map(|min| (min.clone(), String::new()));
+        let upper = max.map(|max| (max.clone() + 1u8, String::new()));
+
+        // inverted bounds are an empty range
+        let upper = match (&lower, upper) {
+            (Some(lower), Some(upper)) if upper <= *lower => Some(lower.clone()),
+            (_, upper) => upper,
+        };
+
+        let start = lower.map_or(Bound::Unbounded, Bound::Included);
+        let end = upper.map_or(Bound::Unbounded, Bound::Excluded);
+        self.sorted.range((start, end)).map(|(_, id)| id)
+    }
+
+    /// token ids before an entry, in ascending order
+    pub fn before(
+        &self,
+        value: Nat,
+        token_id: String,
+    ) -> impl DoubleEndedIterator<Item = &String> + Clone {
+        self.sorted
+            .range((Bound::Unbounded, Bound::Excluded((value, token_id))))
+            .map(|(_, id)| id)
+    }
+
+    /// token ids after an entry, in ascending order
+    pub fn after(
+        &self,
+        value: Nat,
+        token_id: String,
+    ) -> impl DoubleEndedIterator<Item = &String> + Clone {
+        self.sorted
+            .range((Bound::Excluded((value, token_id)), Bound::Unbounded))
+            .map(|(_, id)| id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids<'a>(tokens: impl Iterator<Item = &'a String>) -> Vec<&'a str> {
+        tokens.map(|id| id.as_str()).collect()
+    }
+
+    /// index of tokens "a".."d" with values 1, 2, 2, 3
+    fn setup() -> SortIndex {
+        let mut index = SortIndex::default();
+        for (id, value) in [("c", 2u64), ("a", 1), ("d", 3), ("b", 2)] {
+            index.insert(id.to_string(), value.into());
+        }
+        index
+    }
+
+    #[test]
+    fn orders_by_value_then_id() {
+        let index = setup();
+        assert_eq!(index.len(), 4);
+        assert_eq!(ids(index.iter()), vec!["a", "b", "c", "d"]);
+        assert_eq!(ids(index.iter().rev()), vec!["d", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn insert_moves_an_entry() {
+        let mut index = setup();
+        index.insert("a".to_string(), 5u64.into());
+
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.value("a"), Some(&5u64.into()));
+        assert_eq!(ids(index.iter()), vec!["b", "c", "d", "a"]);
+    }
+
+    #[test]
+    fn remove() {
+        let mut index = setup();
+        assert!(index.remove("b"));
+        assert!(!index.remove("b"));
+
+        assert!(!index.contains("b"));
+        assert_eq!(index.value("b"), None);
+        assert_eq!(ids(index.iter()), vec!["a", "c", "d"]);
+    }
+
+    #[test]
+    fn range() {
+        let index = setup();
+        let (one, two, three) = (1u64.into(), 2u64.into(), 3u64.into());
+
+        // bounds are inclusive
+        assert_eq!(ids(index.range(Some(&two), Some(&two))), vec!["b", "c"]);
+        assert_eq!(ids(index.range(Some(&two), None)), vec!["b", "c", "d"]);
+        assert_eq!(ids(index.range(None, Some(&one))), vec!["a"]);
+        assert_eq!(ids(index.range(None, None)).len(), 4);
+
+        // inverted bounds are empty
+        assert!(ids(index.range(Some(&three), Some(&one))).is_empty());
+    }
+
+    #[test]
+    fn before_and_after() {
+        let index = setup();
+
+        assert_eq!(
+            ids(index.before(2u64.into(), "c".to_string())),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            ids(index.after(2u64.into(), "b".to_string())),
+            vec!["c", "d"]
+        );
+
+        // entries that are not in the index still seek to their position
+        assert_eq!(
+            ids(index.after(2u64.into(), "bb".to_string())),
+            vec!["c", "d"]
+        );
+        assert!(ids(index.before(1u64.into(), "a".to_string())).is_empty());
+    }
+}
@@ -1,11 +1,16 @@
+use crate::clock::Clock;
 use crate::db::*;
-use candid::Principal;
+use crate::types::*;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::storage::{stable_restore, stable_save};
 use std::cell::RefCell;
+use std::collections::HashSet;
 
 thread_local! {
   pub static LEDGER: RefCell<Ledger>  = RefCell::new(Ledger::new());
 }
 
+#[derive(CandidType, Deserialize)]
 pub struct Ledger {
     pub nft_canister_id: Principal,
     pub custodians: Vec<Principal>,
@@ -20,6 +25,168 @@ impl Ledger {
             db: Database::new(),
         }
     }
+
+    /// set the nft canister to accept data for, and the first custodian
+    pub fn init(&mut self, nft_canister_id: Option<Principal>, custodian: Principal) {
+        self.nft_canister_id = nft_canister_id.unwrap_or(Principal::management_canister());
+        if !self.custodians.contains(&custodian) {
+            self.custodians.push(custodian);
+        }
+    }
+
+    pub fn is_custodian(&self, principal: &Principal) -> bool {
+        self.custodians.contains(principal)
+    }
+
+    pub fn add_custodian(&mut self, principal: Principal) -> Result<(), &'static str> {
+        if self.is_custodian(&principal) {
+            return Err("Principal is already a custodian");
+        }
+
+        self.custodians.push(principal);
+        Ok(())
+    }
+
+    /// remove a custodian. the last custodian cannot be removed.
+    pub fn remove_custodian(&mut self, principal: &Principal) -> Result<(), &'static str> {
+        if !self.is_custodian(principal) {
+            return Err("Principal is not a custodian");
+        }
+        if self.custodians.len() == 1 {
+            return Err("Cannot remove the last custodian");
+        }
+
+        self.custodians.retain(|c| c != principal);
+        Ok(())
+    }
+
+    /// check if an event can be indexed, without modifying the ledger.
+    ///
+    /// `pending_minted` and `pending_burned` hold token ids minted and burned by earlier events
+    /// of the same batch.
+    pub fn validate_event(
+        &self,
+        event: &Event,
+        pending_minted: &HashSet<String>,
+        pending_burned: &HashSet<String>,
+    ) -> Result<(), &'static str> {
+        if event.nft_canister_id != self.nft_canister_id {
+            return Err("Not accepting data for this canister");
+        }
+
+        self.db
+            .validate_event(event, pending_minted, pending_burned)
+    }
+
+    pub fn index_event(
+        &mut self,
+        event: Event,
+        clock: &impl Clock,
+    ) -> Result<IndexResult, &'static str> {
+        if event.nft_canister_id != self.nft_canister_id {
+            return Err("Not accepting data for this canister");
+        }
+
+        self.db.index_event(event, clock)
+    }
+
+    /// index a batch of events.
+    ///
+    /// In atomic mode, the whole batch is validated first and nothing is indexed if any event is
+    /// invalid. In best effort mode, events are indexed in order and invalid events are skipped.
+    pub fn index_batch(
+        &mut self,
+        events: Vec<Event>,
+        mode: BatchMode,
+        clock: &impl Clock,
+    ) -> BatchResponse {
+        let mut errors = vec![];
+        let mut results = vec![];
+
+        if mode == BatchMode::Atomic {
+            let mut pending_minted = HashSet::new();
+            let mut pending_burned = HashSet::new();
+            for (index, event) in events.iter().enumerate() {
+                // already applied events will be skipped
+                if self.db.is_applied(event) {
+                    results.push(Ok(IndexResult::AlreadyApplied));
+                    continue;
+                }
+
+                match self.validate_event(event, &pending_minted, &pending_burned) {
+                    Ok(_) => match event.operation {
+                        Operation::Mint { .. } => {
+                            pending_burned.remove(&event.token_id);
+                            pending_minted.insert(event.token_id.clone());
+                        }
+                        Operation::Burn => {
+                            pending_minted.remove(&event.token_id);
+                            pending_burned.insert(event.token_id.clone());
+                        }
+                        _ => {}
+                    },
+                    Err(e) => errors.push(EventError {
+                        index,
+                        error: e.to_string(),
+                    }),
+                }
+                results.push(match errors.last() {
+                    Some(error) if error.index == index => Err(error.error.clone()),
+                    _ => Err("Batch rejected, another event is invalid".to_string()),
+                });
+            }
+
+            if !errors.is_empty() {
+                return BatchResponse {
+                    applied: 0,
+                    already_applied: 0,
+                    errors,
+                    results,
+                };
+            }
+            results.clear();
+        }
+
+        let mut applied = 0;
+        let mut already_applied = 0;
+        for (index, event) in events.into_iter().enumerate() {
+            let result = self.index_event(event, clock);
+            results.push(result.clone().map_err(|e| e.to_string()));
+
+            match result {
+                Ok(IndexResult::Applied) => applied += 1,
+                Ok(IndexResult::AlreadyApplied) => already_applied += 1,
+                Err(e) => {
+                    if mode == BatchMode::Atomic {
+                        // validated events should never fail, trap to roll back the whole batch
+                        ic_cdk::trap(&format!("event {} failed after validation: {}", index, e));
+                    }
+
+                    errors.push(EventError {
+                        index,
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        BatchResponse {
+            applied,
+            already_applied,
+            errors,
+            results,
+        }
+    }
+}
+
+/// Versioned stable memory layout.
+///
+/// When a change to `Ledger`, `Database` or `TokenData` can no longer be decoded from
+/// the previous layout, freeze the old types under a new name, add a variant for the
+/// new layout, and migrate the old variant in `restore`.
+#[derive(CandidType, Deserialize)]
+pub enum StableLedger {
+    V1(Ledger),
 }
 
 pub fn with<T, F: FnOnce(&Ledger) -> T>(f: F) -> T {
@@ -29,3 +196,215 @@ pub fn with<T, F: FnOnce(&Ledger) -> T>(f: F) -> T {
 pub fn with_mut<T, F: FnOnce(&mut Ledger) -> T>(f: F) -> T {
     LEDGER.with(|ledger| f(&mut ledger.borrow_mut()))
 }
+
+/// write the ledger to stable memory. the ledger is moved out of the heap, as the
+/// canister is about to be replaced anyways.
+pub fn save() -> Result<(), String> {
+    let ledger = with_mut(|ledger| std::mem::replace(ledger, Ledger::new()));
+
+    stable_save((StableLedger::V1(ledger),)).map_err(|e| e.to_string())
+}
+
+/// check if a ledger was saved to stable memory. Releases without a `pre_upgrade` hook did
+/// not save anything.
+pub fn is_saved() -> bool {
+    ic_cdk::api::stable::stable_size() > 0
+}
+
+/// read the ledger from stable memory, migrating from older layouts if needed.
+pub fn restore() -> Result<(), String> {
+    let (stable,): (StableLedger,) = stable_restore()?;
+
+    // migrate older layouts here as they are added
+    let StableLedger::V1(ledger) = stable;
+
+    with_mut(|current| *current = ledger);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn event(token_id: &str, operation: Operation) -> Event {
+        Event {
+            nft_canister_id: Principal::management_canister(),
+            fungible_id: None,
+            token_id: token_id.to_string(),
+            operation,
+            seller: None,
+            event_id: None,
+            time: None,
+        }
+    }
+
+    fn mint(token_id: &str) -> Event {
+        event(
+            token_id,
+            Operation::Mint {
+                traits: None,
+                to: None,
+            },
+        )
+    }
+
+    #[test]
+    fn rejects_other_canisters() {
+        let mut ledger = Ledger::new();
+        let event = Event {
+            nft_canister_id: Principal::anonymous(),
+            ..mint("0")
+        };
+
+        assert!(ledger.index_event(event, &MockClock::new(0)).is_err());
+    }
+
+    #[test]
+    fn manages_custodians() {
+        let (alice, bob) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let mut ledger = Ledger::new();
+        ledger.init(None, alice);
+        assert!(ledger.is_custodian(&alice));
+        assert!(!ledger.is_custodian(&bob));
+
+        assert!(ledger.add_custodian(bob).is_ok());
+        assert!(ledger.add_custodian(bob).is_err());
+        assert!(ledger.is_custodian(&bob));
+
+        assert!(ledger.remove_custodian(&alice).is_ok());
+        assert!(ledger.remove_custodian(&alice).is_err());
+        assert!(!ledger.is_custodian(&alice));
+
+        // the last custodian is kept
+        assert_eq!(
+            ledger.remove_custodian(&bob),
+            Err("Cannot remove the last custodian")
+        );
+        assert!(ledger.is_custodian(&bob));
+    }
+
+    #[test]
+    fn atomic_batch_indexes_nothing_on_error() {
+        let mut ledger = Ledger::new();
+        let events = vec![
+            mint("0"),
+            event("0", Operation::MakeListing { price: 1.into() }),
+            event("1", Operation::CancelListing),
+        ];
+
+        let response = ledger.index_batch(events, BatchMode::Atomic, &MockClock::new(0));
+        assert_eq!(response.applied, 0);
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].index, 2);
+        assert_eq!(response.results[2], Err("Token not indexed".to_string()));
+        assert!(response.results[..2].iter().all(|result| result.is_err()));
+        assert!(ledger.db.get(&"0".to_string()).is_none());
+    }
+
+    #[test]
+    fn atomic_batch_tracks_burns() {
+        let mut ledger = Ledger::new();
+        ledger.index_event(mint("0"), &MockClock::new(0)).unwrap();
+
+        // operations on a token burned earlier in the batch are invalid
+        for events in [
+            vec![
+                mint("1"),
+                event("1", Operation::Burn),
+                event("1", Operation::MakeListing { price: 1.into() }),
+            ],
+            vec![
+                event("0", Operation::Burn),
+                event("0", Operation::CancelListing),
+            ],
+        ] {
+            let response = ledger.index_batch(events, BatchMode::Atomic, &MockClock::new(0));
+            assert_eq!(response.applied, 0);
+            assert_eq!(response.errors.len(), 1);
+            assert_eq!(response.errors[0].error, "Token not indexed");
+        }
+        assert!(ledger.db.get(&"0".to_string()).is_some());
+
+        // until it is minted again
+        let events = vec![
+            event("0", Operation::Burn),
+            mint("0"),
+            event("0", Operation::MakeListing { price: 1.into() }),
+        ];
+        let response = ledger.index_batch(events, BatchMode::Atomic, &MockClock::new(0));
+        assert_eq!(response.applied, 3);
+        assert!(response.errors.is_empty());
+    }
+
+    #[test]
+    fn best_effort_batch_skips_errors() {
+        let mut ledger = Ledger::new();
+        let events = vec![
+            mint("0"),
+            event("1", Operation::CancelListing),
+            Event {
+                event_id: Some(1),
+                ..event("0", Operation::MakeListing { price: 1.into() })
+            },
+            Event {
+                event_id: Some(1),
+                ..event("0", Operation::MakeListing { price: 1.into() })
+            },
+        ];
+
+        let response = ledger.index_batch(events, BatchMode::BestEffort, &MockClock::new(0));
+        assert_eq!(response.applied, 2);
+        assert_eq!(response.already_applied, 1);
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].index, 1);
+        assert_eq!(
+            response.results,
+            vec![
+                Ok(IndexResult::Applied),
+                Err("Token not indexed".to_string()),
+                Ok(IndexResult::Applied),
+                Ok(IndexResult::AlreadyApplied),
+            ]
+        );
+        assert_eq!(
+            ledger.db.get(&"0".to_string()).unwrap().price,
+            Some(1.into())
+        );
+    }
+
+    #[test]
+    fn stable_layout_round_trips() {
+        let mut ledger = Ledger::new();
+        ledger.init(None, Principal::anonymous());
+        let events = vec![
+            mint("0"),
+            event("0", Operation::MakeListing { price: 1.into() }),
+            Event {
+                event_id: Some(7),
+                ..event(
+                    "0",
+                    Operation::MakeOffer {
+                        buyer: Principal::anonymous(),
+                        price: 2.into(),
+                    },
+                )
+            },
+        ];
+        ledger.index_batch(events, BatchMode::Atomic, &MockClock::new(0));
+
+        let bytes = candid::encode_one(StableLedger::V1(ledger)).unwrap();
+        let StableLedger::V1(restored) = candid::decode_one(&bytes).unwrap();
+
+        assert_eq!(restored.custodians, vec![Principal::anonymous()]);
+        assert_eq!(restored.db.get_events(None, None).total, 3);
+        assert!(restored.db.is_applied(&Event {
+            event_id: Some(7),
+            ..mint("1")
+        }));
+        let token = restored.db.get(&"0".to_string()).unwrap();
+        assert_eq!(token.price, Some(1.into()));
+        assert_eq!(token.best_offer, Some(2.into()));
+    }
+}
@@ -1,12 +1,18 @@
+use crate::clock::IcClock;
 use crate::types::*;
 use candid::{candid_method, export_service, Principal};
 use ic_cdk::caller;
 use ic_cdk_macros::*;
 use std::vec;
 
+mod clock;
 mod db;
+mod index;
 mod ledger;
 mod types;
+
+#[cfg(test)]
+mod bench;
 // mod proxy;
 
 /* QUERY METHODS */
@@ -21,37 +27,218 @@ fn query(request: QueryRequest) -> QueryResponse {
     ledger::with(|ledger| ledger.db.query(request))
 }
 
+/// get a token by id.
+#[query]
+#[candid_method(query)]
+fn get_token(token_id: String) -> Result<TokenData, &'static str> {
+    ledger::with(|ledger| ledger.db.get(&token_id).cloned().ok_or("Token not indexed"))
+}
+
+/// get tokens by id, in the order of the ids. Unknown ids are `Err("Token not indexed")`.
+///
+/// # Arguments
+/// * `token_ids` - token ids, max 64
+#[query]
+#[candid_method(query)]
+fn get_tokens(
+    token_ids: Vec<String>,
+) -> Result<Vec<Result<TokenData, &'static str>>, &'static str> {
+    ledger::with(|ledger| ledger.db.get_tokens(token_ids))
+}
+
+/// query the tokens of an owner, sorted and filtered like `query`.
+///
+/// # Arguments
+/// * `owner` - owner principal.
+/// * `request` - query request.
+#[query]
+#[candid_method(query)]
+fn tokens_of(owner: Principal, request: QueryRequest) -> QueryResponse {
+    ledger::with(|ledger| ledger.db.tokens_of(&owner, request))
+}
+
+/// count tokens for each trait value.
+///
+/// # Arguments
+/// * `request` - facet request.
+#[query]
+#[candid_method(query)]
+fn facets(request: FacetRequest) -> FacetResponse {
+    ledger::with(|ledger| ledger.db.facets(request))
+}
+
+/// get collection statistics: floor price, listed tokens and sale volume.
+#[query]
+#[candid_method(query)]
+fn get_stats() -> Stats {
+    ledger::with(|ledger| ledger.db.get_stats(&IcClock))
+}
+
+/// get sale and floor price candles of a fungible for charting. Candles without activity are
+/// left out.
+///
+/// # Arguments
+/// * `fungible` - fungible of the prices.
+/// * `interval` - candle size.
+/// * `from` - start time, in nanoseconds.
+/// * `to` - end time, in nanoseconds. At most 1000 candles are returned.
+#[query]
+#[candid_method(query)]
+fn get_timeseries(fungible: Principal, interval: Interval, from: u64, to: u64) -> Vec<Candle> {
+    ledger::with(|ledger| ledger.db.get_timeseries(&fungible, interval, from, to))
+}
+
+/// get a page of a token's listings, offers and sales, newest first.
+///
+/// # Arguments
+/// * `token_id` - token id.
+/// * `start` - number of entries to skip. Default is 0.
+/// * `count` - number of entries to return. Default is 10, max 64
+#[query]
+#[candid_method(query)]
+fn get_token_history(
+    token_id: String,
+    start: Option<usize>,
+    count: Option<usize>,
+) -> HistoryResponse {
+    ledger::with(|ledger| ledger.db.get_token_history(&token_id, start, count))
+}
+
+/// get a page of the tokens a buyer has open offers on.
+///
+/// # Arguments
+/// * `buyer` - buyer principal.
+/// * `start` - number of entries to skip. Default is 0.
+/// * `count` - number of entries to return. Default is 10, max 64
+#[query]
+#[candid_method(query)]
+fn offers_of(buyer: Principal, start: Option<usize>, count: Option<usize>) -> TokensResponse {
+    ledger::with(|ledger| ledger.db.offers_of(&buyer, start, count))
+}
+
+/// get a page of the tokens a seller has listed.
+///
+/// # Arguments
+/// * `seller` - seller principal.
+/// * `start` - number of entries to skip. Default is 0.
+/// * `count` - number of entries to return. Default is 10, max 64
+#[query]
+#[candid_method(query)]
+fn listings_of(seller: Principal, start: Option<usize>, count: Option<usize>) -> TokensResponse {
+    ledger::with(|ledger| ledger.db.listings_of(&seller, start, count))
+}
+
+/// get a page of a buyer's purchases, newest first.
+///
+/// # Arguments
+/// * `buyer` - buyer principal.
+/// * `start` - number of entries to skip. Default is 0.
+/// * `count` - number of entries to return. Default is 10, max 64
+#[query]
+#[candid_method(query)]
+fn purchases_of(buyer: Principal, start: Option<usize>, count: Option<usize>) -> SalesResponse {
+    ledger::with(|ledger| ledger.db.purchases_of(&buyer, start, count))
+}
+
+/// get a page of a seller's sales, newest first.
+///
+/// # Arguments
+/// * `seller` - seller principal.
+/// * `start` - number of entries to skip. Default is 0.
+/// * `count` - number of entries to return. Default is 10, max 64
+#[query]
+#[candid_method(query)]
+fn sales_of(seller: Principal, start: Option<usize>, count: Option<usize>) -> SalesResponse {
+    ledger::with(|ledger| ledger.db.sales_of(&seller, start, count))
+}
+
+/// get the exchange rates used by the normalized price sort keys
+#[query]
+#[candid_method(query)]
+fn get_rates() -> Vec<(Principal, FungibleRate)> {
+    ledger::with(|ledger| ledger.db.get_rates())
+}
+
+/// get a page of the event log.
+///
+/// # Arguments
+/// * `start` - sequence number to start from. Default is 0.
+/// * `count` - number of events to return. Default is 10, max 64
+#[query]
+#[candid_method(query)]
+fn get_events(start: Option<u64>, count: Option<usize>) -> EventsResponse {
+    ledger::with(|ledger| ledger.db.get_events(start, count))
+}
+
+/// get the list of custodians allowed to write to the index
+#[query]
+#[candid_method(query)]
+fn get_custodians() -> Vec<Principal> {
+    ledger::with(|ledger| ledger.custodians.clone())
+}
+
 /* UPDATE METHODS */
 
-/// insert token transaction
-#[update]
+/// insert token transaction. Tokens must be indexed with a `mint` event before any other event.
+#[update(guard = "is_custodian")]
 #[candid_method(update)]
-fn insert(event: Event) -> Result<(), &'static str> {
-    ledger::with_mut(|ledger| {
-        if event.nft_canister_id != ledger.nft_canister_id {
-            return Err("Not accepting data for this canister");
-        }
-
-        ledger.db.index_event(event)
-    })
+fn insert(event: Event) -> Result<IndexResult, &'static str> {
+    ledger::with_mut(|ledger| ledger.index_event(event, &IcClock))
 }
 
 /// batch insert token transactions
-#[update]
+///
+/// # Arguments
+/// * `events` - events to index, in order. Tokens must be indexed with a `mint` event, earlier
+///   in the batch or before it, ahead of any other event.
+/// * `mode` - `atomic` (default) or `bestEffort`. See `BatchMode`.
+#[update(guard = "is_custodian")]
 #[candid_method(update)]
-fn batch_insert(events: Vec<Event>) -> Result<(), &'static str> {
+fn batch_insert(events: Vec<Event>, mode: Option<BatchMode>) -> BatchResponse {
     ledger::with_mut(|ledger| {
-        for event in events {
-            if event.nft_canister_id != ledger.nft_canister_id {
-                return Err("Not accepting data for this canister");
-            }
-            match ledger.db.index_event(event) {
-                Ok(_) => (),
-                Err(e) => return Err(e),
-            }
-        }
+        ledger.index_batch(events, mode.unwrap_or(BatchMode::Atomic), &IcClock)
+    })
+}
+
+/// drop all indexes and rebuild them by replaying the event log.
+/// Returns the number of replayed events.
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+fn rebuild_indexes() -> usize {
+    ledger::with_mut(|ledger| ledger.db.rebuild_indexes())
+}
+
+/// set the exchange rate of a fungible to the common unit of the normalized price sort keys,
+/// or remove it with `null`. Returns the number of re-sorted tokens.
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+fn set_rate(fungible: Principal, rate: Option<FungibleRate>) -> usize {
+    ledger::with_mut(|ledger| ledger.db.set_rate(fungible, rate))
+}
 
-        Ok(())
+/// add a principal to the custodian list
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+fn add_custodian(principal: Principal) -> Result<(), &'static str> {
+    ledger::with_mut(|ledger| ledger.add_custodian(principal))
+}
+
+/// remove a principal from the custodian list. the last custodian cannot be removed.
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+fn remove_custodian(principal: Principal) -> Result<(), &'static str> {
+    ledger::with_mut(|ledger| ledger.remove_custodian(&principal))
+}
+
+/* GUARDS */
+
+fn is_custodian() -> Result<(), String> {
+    ledger::with(|ledger| {
+        if ledger.is_custodian(&caller()) {
+            Ok(())
+        } else {
+            Err("Caller is not a custodian".to_string())
+        }
     })
 }
 
@@ -60,13 +247,24 @@ fn batch_insert(events: Vec<Event>) -> Result<(), &'static str> {
 #[init]
 #[candid_method(init)]
 fn init(nft_canister_id: Option<Principal>) {
-    ledger::with_mut(|ledger| {
-        ledger.nft_canister_id = nft_canister_id.unwrap_or(Principal::management_canister());
-        ledger.custodians.push(caller());
-    });
+    ledger::with_mut(|ledger| ledger.init(nft_canister_id, caller()));
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    ledger::save().expect("failed to save ledger to stable memory");
 }
 
-// TODO: Upgrade logic
+/// restore the ledger saved by `pre_upgrade`. When upgrading from a release that did not save
+/// it, start from an empty ledger set up like `init`, with the caller as custodian.
+#[post_upgrade]
+fn post_upgrade(nft_canister_id: Option<Principal>) {
+    if ledger::is_saved() {
+        ledger::restore().expect("failed to restore ledger from stable memory");
+    } else {
+        ledger::with_mut(|ledger| ledger.init(nft_canister_id, caller()));
+    }
+}
 
 #[query(name = "__get_candid_interface_tmp_hack")]
 fn export_candid() -> String {
@@ -1,8 +1,12 @@
-use candid::{CandidType, Deserialize, Nat, Principal};
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
 use std::collections::HashMap;
 
 pub const DEFAULT_PAGE_SIZE: usize = 10;
 pub const PAGE_SIZE_LIMIT: usize = 64;
+pub const TOKEN_HISTORY_LIMIT: usize = 100;
+pub const TIMESERIES_LIMIT: usize = 1_000;
+pub const HOUR: u64 = 3_600_000_000_000;
+pub const DAY: u64 = 24 * HOUR;
 
 #[derive(CandidType, Clone, Deserialize, Debug, Hash, Eq, PartialEq)]
 pub enum GenericValue {
@@ -22,6 +26,24 @@ pub enum GenericValue {
     NestedContent(Vec<(String, GenericValue)>),
 }
 
+impl GenericValue {
+    /// value of a numeric variant, comparable across widths and signedness
+    pub fn as_int(&self) -> Option<Int> {
+        match self {
+            GenericValue::Nat8Content(n) => Some(Int::from(*n)),
+            GenericValue::Nat16Content(n) => Some(Int::from(*n)),
+            GenericValue::Nat32Content(n) => Some(Int::from(*n)),
+            GenericValue::Nat64Content(n) => Some(Int::from(*n)),
+            GenericValue::NatContent(n) => Some(Int::from(n.clone())),
+            GenericValue::Int8Content(n) => Some(Int::from(*n)),
+            GenericValue::Int16Content(n) => Some(Int::from(*n)),
+            GenericValue::Int32Content(n) => Some(Int::from(*n)),
+            GenericValue::Int64Content(n) => Some(Int::from(*n)),
+            _ => None,
+        }
+    }
+}
+
 /// Query Request
 ///
 /// ### Required Arguments
@@ -34,49 +56,495 @@ pub enum GenericValue {
 ///   - `last_offer` - recently modified tokens.
 ///   - `last_sale` - recently sold tokens.
 ///   - `all` - all indexed tokens.
+///   - `normalized_listing_price`, `normalized_offer_price`, `normalized_sale_price` - prices converted to a common unit with the custodian set exchange rates. Prices in fungibles without a rate are left out.
 /// * `page` - page number. If `null`, returns the last (most recent) page of results. Order is backwards
 ///
 /// ### Optional Arguments
 ///
 /// * `count` - number of results to return. Default is 10, max 64
-/// * `offset` - For complicated filter queries past the first page (0), specify this parameter to hint the previous request left off at a specific point in the index. Default is 0.
-/// * `traits` - filter results by traits. Passed as a vec of (key, value) tuples.
+/// * `last_index` - For complicated filter queries past the first page (0), specify this parameter to hint the previous request left off at a specific point in the index. Default is 0.
+/// * `cursor` - cursor returned by the previous request, to resume from where it left off. Unlike `last_index`, it is not affected by tokens inserted or removed in between requests. Takes precedence over `last_index`.
+/// * `traits` - filter results by traits. Passed as a vec of (key, value) tuples. Tokens matching any of the traits are returned.
+/// * `filter` - filter results by a trait filter expression. If `traits` is also provided, tokens must match both.
+/// * `ranges` - filter results by sort value ranges, ie. listing price or last sale time. Tokens must match all of the ranges.
 /// * `reverse` - Default: false. If true, returns results in reverse (ascending) order
+/// * `fungible` - only sort and filter prices in this fungible. Only applies to the `listing_price`, `offer_price` and `sale_price` sort keys and ranges.
 #[derive(CandidType, Clone, Deserialize)]
 pub struct QueryRequest {
     pub sort_key: String,
     pub last_index: Option<usize>,
+    pub cursor: Option<String>,
     pub count: Option<usize>,
     pub traits: Option<Vec<(String, GenericValue)>>,
+    pub filter: Option<TraitFilter>,
+    pub ranges: Option<Vec<RangeFilter>>,
     pub reverse: Option<bool>,
+    pub fungible: Option<Principal>,
+}
+
+/// Range filter over the sort value of a sort index
+///
+/// * `sort_key` - sort index to filter by. Possible options are the same as `QueryRequest`, ie:
+///   - `listing_price` - listing price.
+///   - `offer_price` - best offer price.
+///   - `sale_price` - last sale price.
+///   - `last_listing`, `last_offer`, `last_sale` - time of the last listing, offer, or sale.
+/// * `min` - inclusive lower bound. If `null`, the range is unbounded below.
+/// * `max` - inclusive upper bound. If `null`, the range is unbounded above.
+///
+/// Only tokens in the sort index match, ie. a `listing_price` range only matches listed tokens.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct RangeFilter {
+    pub sort_key: String,
+    pub min: Option<Nat>,
+    pub max: Option<Nat>,
 }
 
+/// Trait filter expression
+///
+/// * `trait` - tokens with the (key, value) trait.
+/// * `traitRange` - tokens with a numeric trait value within the inclusive `min`/`max` bounds.
+///   Numeric variants of any width are compared by value. A `null` bound is unbounded.
+/// * `and` - tokens matching all of the filters.
+/// * `or` - tokens matching any of the filters.
+/// * `not` - tokens not matching the filter.
+///
+/// For example, "any of the selected values within a key, for all of the selected keys"
+/// is an `and` of one `or` per trait key.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub enum TraitFilter {
+    #[serde(rename = "trait")]
+    Trait(String, GenericValue),
+    #[serde(rename = "traitRange")]
+    TraitRange {
+        key: String,
+        min: Option<Int>,
+        max: Option<Int>,
+    },
+    #[serde(rename = "and")]
+    And(Vec<TraitFilter>),
+    #[serde(rename = "or")]
+    Or(Vec<TraitFilter>),
+    #[serde(rename = "not")]
+    Not(Box<TraitFilter>),
+}
+
+/// Query Response
+///
+/// * `total` - number of tokens in the sort index matching the traits and filter.
+/// * `last_index` - position the next page starts from, if any. Only returned for pages
+///   requested without a `cursor`.
+/// * `cursor` - opaque cursor to request the next page with, if any.
+/// * `data` - token data.
+/// * `error` - error message, if any.
 #[derive(CandidType, Clone, Debug)]
 pub struct QueryResponse {
     pub total: usize,
     pub last_index: Option<usize>,
+    pub cursor: Option<String>,
     pub data: Vec<TokenData>,
     pub error: Option<String>,
 }
 
+/// Event operation, carrying the data required by each operation.
+///
+/// Every operation other than `mint` requires the token to be minted in the index first, and is
+/// rejected with "Token not indexed" otherwise.
+///
+/// `directBuy` and `acceptOffer` transfer the token to the buyer. `transfer` cancels the
+/// token's listing, as it was made by the previous owner. `burn` removes the token from the
+/// db and every index, only past sales are kept in the principal sale indexes and stats.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub enum Operation {
+    #[serde(rename = "mint")]
+    Mint {
+        traits: Option<HashMap<String, GenericValue>>,
+        to: Option<Principal>,
+    },
+    #[serde(rename = "transfer")]
+    Transfer { to: Principal },
+    #[serde(rename = "burn")]
+    Burn,
+    #[serde(rename = "updateMetadata")]
+    UpdateMetadata {
+        traits: Option<HashMap<String, GenericValue>>,
+    },
+    #[serde(rename = "makeListing")]
+    MakeListing { price: Nat },
+    #[serde(rename = "cancelListing")]
+    CancelListing,
+    #[serde(rename = "makeOffer")]
+    MakeOffer { buyer: Principal, price: Nat },
+    #[serde(rename = "cancelOffer")]
+    CancelOffer { buyer: Principal },
+    #[serde(rename = "directBuy")]
+    DirectBuy { buyer: Principal, price: Nat },
+    #[serde(rename = "acceptOffer")]
+    AcceptOffer { buyer: Principal, price: Nat },
+}
+
+/// Facet Request
+///
+/// ### Optional Arguments
+///
+/// * `sort_key` - only count tokens in the sort index, ie. `listing_price` for listed tokens. See `QueryRequest`.
+/// * `traits` - only count tokens matching any of the (key, value) traits. See `QueryRequest`.
+/// * `filter` - only count tokens matching the trait filter expression. See `QueryRequest`.
+/// * `ranges` - only count tokens matching all of the sort value ranges. See `QueryRequest`.
+#[derive(CandidType, Clone, Deserialize)]
+pub struct FacetRequest {
+    pub sort_key: Option<String>,
+    pub traits: Option<Vec<(String, GenericValue)>>,
+    pub filter: Option<TraitFilter>,
+    pub ranges: Option<Vec<RangeFilter>>,
+}
+
+/// Facet value
+///
+/// * `value` - trait value.
+/// * `total` - number of tokens in the collection with the trait value.
+/// * `count` - number of tokens matching the request with the trait value.
+#[derive(CandidType, Clone, Debug)]
+pub struct FacetValue {
+    pub value: GenericValue,
+    pub total: usize,
+    pub count: usize,
+}
+
+#[derive(CandidType, Clone, Debug)]
+pub struct Facet {
+    pub key: String,
+    pub values: Vec<FacetValue>,
+}
+
+/// Facet Response
+///
+/// * `facets` - trait keys in alphabetical order, with their values ordered by count.
+/// * `error` - error message, if any.
+#[derive(CandidType, Clone, Debug)]
+pub struct FacetResponse {
+    pub facets: Vec<Facet>,
+    pub error: Option<String>,
+}
+
 #[derive(CandidType, Clone, Deserialize, Debug)]
 pub struct Event {
     pub nft_canister_id: Principal,
     pub fungible_id: Option<Principal>,
     pub token_id: String,
-    pub operation: String,
+    pub operation: Operation,
 
-    pub traits: Option<HashMap<String, GenericValue>>,
-    pub price: Option<Nat>,
-    pub buyer: Option<Principal>,
     pub seller: Option<Principal>,
+    // id or sequence number of the event at its source (ie. cap transaction id).
+    // events with an id that was already applied are skipped.
+    pub event_id: Option<u64>,
+    // time the event happened at, in nanoseconds. defaults to the time it is indexed at.
+    pub time: Option<u64>,
 }
 
+/// Result of indexing an event
+///
+/// * `applied` - the event was indexed.
+/// * `alreadyApplied` - an event with the same `event_id` was already indexed, nothing was changed.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub enum IndexResult {
+    #[serde(rename = "applied")]
+    Applied,
+    #[serde(rename = "alreadyApplied")]
+    AlreadyApplied,
+}
+
+/// Event log entry
+///
+/// * `seq` - sequence number of the event in the log.
+/// * `time` - time the event happened at, or was indexed at if the event has no time.
+/// * `event` - the original event.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct LoggedEvent {
+    pub seq: u64,
+    pub time: u64,
+    pub event: Event,
+}
+
+/// Event log page
+///
+/// * `total` - number of events in the log.
+/// * `next` - sequence number to request the next page from, if any.
+/// * `data` - logged events, in ascending sequence order.
+#[derive(CandidType, Clone, Debug)]
+pub struct EventsResponse {
+    pub total: u64,
+    pub next: Option<u64>,
+    pub data: Vec<LoggedEvent>,
+}
+
+/// Token activity
+///
+/// * `listing` - the token was listed for `price`, in `fungible`.
+/// * `offer` - `buyer` made an offer for `price`.
+/// * `sale` - the token was sold to `buyer` for `price`.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub enum Activity {
+    #[serde(rename = "listing")]
+    Listing { fungible: Principal, price: Nat },
+    #[serde(rename = "offer")]
+    Offer {
+        buyer: Principal,
+        fungible: Principal,
+        price: Nat,
+    },
+    #[serde(rename = "sale")]
+    Sale {
+        buyer: Principal,
+        fungible: Principal,
+        price: Nat,
+    },
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct HistoryEntry {
+    pub time: u64,
+    pub activity: Activity,
+}
+
+/// Token history page
+///
+/// * `total` - number of history entries kept for the token, up to `TOKEN_HISTORY_LIMIT`.
+/// * `next` - offset to request the next page from, if any.
+/// * `data` - history entries, newest first.
+#[derive(CandidType, Clone, Debug)]
+pub struct HistoryResponse {
+    pub total: usize,
+    pub next: Option<usize>,
+    pub data: Vec<HistoryEntry>,
+}
+
+/// Token page
+///
+/// * `total` - number of tokens.
+/// * `next` - offset to request the next page from, if any.
+/// * `data` - tokens, ordered by token id.
+#[derive(CandidType, Clone, Debug)]
+pub struct TokensResponse {
+    pub total: usize,
+    pub next: Option<usize>,
+    pub data: Vec<TokenData>,
+}
+
+/// Sale of a token, indexed by the accounts involved
+///
+/// * `token_id` - token id.
+/// * `time` - time of the sale, in nanoseconds.
+/// * `buyer` - buyer principal.
+/// * `seller` - seller principal, if known.
+/// * `fungible` - fungible the sale was paid in.
+/// * `price` - sale price.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct AccountSale {
+    pub token_id: String,
+    pub time: u64,
+    pub buyer: Principal,
+    pub seller: Option<Principal>,
+    pub fungible: Principal,
+    pub price: Nat,
+}
+
+/// Account sales page
+///
+/// * `total` - number of sales.
+/// * `next` - offset to request the next page from, if any.
+/// * `data` - sales, newest first.
+#[derive(CandidType, Clone, Debug)]
+pub struct SalesResponse {
+    pub total: usize,
+    pub next: Option<usize>,
+    pub data: Vec<AccountSale>,
+}
+
+/// Fungible exchange rate
+///
+/// * `rate` - value of one whole token of the fungible, in the common unit.
+/// * `decimals` - number of decimals of the fungible's amounts.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub struct FungibleRate {
+    pub rate: Nat,
+    pub decimals: u8,
+}
+
+/// Sale volume
+///
+/// * `sales` - number of sales.
+/// * `volume` - sum of sale prices.
+#[derive(CandidType, Clone, Deserialize, Debug, Default, PartialEq)]
+pub struct Volume {
+    pub sales: u64,
+    pub volume: Nat,
+}
+
+/// Time series interval
+///
+/// * `hour` - hourly candles.
+/// * `day` - daily candles.
+#[derive(CandidType, Clone, Copy, Deserialize, Debug, PartialEq)]
+pub enum Interval {
+    #[serde(rename = "hour")]
+    Hour,
+    #[serde(rename = "day")]
+    Day,
+}
+
+impl Interval {
+    /// size of the interval, in nanoseconds
+    pub fn nanos(&self) -> u64 {
+        match self {
+            Interval::Hour => HOUR,
+            Interval::Day => DAY,
+        }
+    }
+}
+
+/// Time series candle
+///
+/// * `start` - start time of the candle, in nanoseconds.
+/// * `sales` - number of sales.
+/// * `volume` - sum of sale prices.
+/// * `min_price` - lowest sale price.
+/// * `max_price` - highest sale price.
+/// * `avg_price` - average sale price, rounded down.
+/// * `floor` - lowest floor price within the candle, while any token was listed.
+/// * `floor_updated` - if the floor changed within the candle. If not, the previous candle's
+///   `floor_close` still applies.
+/// * `floor_close` - floor price at the end of the candle. Empty if no token was listed.
+#[derive(CandidType, Clone, Deserialize, Debug, Default, PartialEq)]
+pub struct Candle {
+    pub start: u64,
+    pub sales: u64,
+    pub volume: Nat,
+    pub min_price: Option<Nat>,
+    pub max_price: Option<Nat>,
+    pub avg_price: Option<Nat>,
+    pub floor: Option<Nat>,
+    pub floor_updated: bool,
+    pub floor_close: Option<Nat>,
+}
+
+impl Candle {
+    pub fn push_sale(&mut self, price: &Nat) {
+        self.sales += 1;
+        self.volume += price.clone();
+        self.min_price = min(self.min_price.take(), Some(price.clone()));
+        self.max_price = self.max_price.take().max(Some(price.clone()));
+        self.avg_price = Some(self.volume.clone() / self.sales);
+    }
+
+    /// record the floor after a listing change, empty if no token is listed
+    pub fn push_floor(&mut self, floor: Option<&Nat>) {
+        self.floor = min(self.floor.take(), floor.cloned());
+        self.floor_updated = true;
+        self.floor_close = floor.cloned();
+    }
+
+    /// merge a later candle into this one
+    pub fn merge(&mut self, other: &Candle) {
+        self.sales += other.sales;
+        self.volume += other.volume.clone();
+        self.min_price = min(self.min_price.take(), other.min_price.clone());
+        self.max_price = self.max_price.take().max(other.max_price.clone());
+        self.floor = min(self.floor.take(), other.floor.clone());
+        if other.floor_updated {
+            self.floor_updated = true;
+            self.floor_close = other.floor_close.clone();
+        }
+        if self.sales > 0 {
+            self.avg_price = Some(self.volume.clone() / self.sales);
+        }
+    }
+}
+
+/// lowest of two optional values, ignoring empty values
+fn min(a: Option<Nat>, b: Option<Nat>) -> Option<Nat> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Price statistics of a fungible
+///
+/// * `fungible` - fungible principal.
+/// * `listed` - number of tokens listed in the fungible.
+/// * `floor` - lowest listing price in the fungible, if any token is listed in it.
+/// * `day` - sales in the last 24 hours, to the hour.
+/// * `week` - sales in the last 7 days, to the hour.
+/// * `all_time` - all sales.
+#[derive(CandidType, Clone, Debug)]
+pub struct FungibleStats {
+    pub fungible: Principal,
+    pub listed: u64,
+    pub floor: Option<Nat>,
+    pub day: Volume,
+    pub week: Volume,
+    pub all_time: Volume,
+}
+
+/// Collection statistics
+///
+/// * `tokens` - number of indexed tokens.
+/// * `owners` - number of principals owning a token, for tokens with a known owner.
+/// * `listed` - number of listed tokens, in any fungible.
+/// * `fungibles` - price statistics of each fungible tokens are listed, offered or sold in.
+///   Prices in different fungibles are never added up.
+#[derive(CandidType, Clone, Debug)]
+pub struct Stats {
+    pub tokens: u64,
+    pub owners: u64,
+    pub listed: u64,
+    pub fungibles: Vec<FungibleStats>,
+}
+
+/// Batch insert mode
+///
+/// * `atomic` - default. The whole batch is validated before any event is indexed. If any event
+///   is invalid, nothing is indexed.
+/// * `bestEffort` - every valid event is indexed, invalid events are skipped.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub enum BatchMode {
+    #[serde(rename = "atomic")]
+    Atomic,
+    #[serde(rename = "bestEffort")]
+    BestEffort,
+}
+
+#[derive(CandidType, Clone, Debug)]
+pub struct EventError {
+    pub index: usize,
+    pub error: String,
+}
+
+/// Batch insert response
+///
+/// * `applied` - number of events indexed.
+/// * `already_applied` - number of events skipped, as their `event_id` was already indexed.
+/// * `errors` - position in the batch and error reason for each rejected event.
+/// * `results` - result of each event, in the order of the batch. When an atomic batch is
+///   rejected, valid events that were not applied have an error as well.
+#[derive(CandidType, Clone, Debug)]
+pub struct BatchResponse {
+    pub applied: usize,
+    pub already_applied: usize,
+    pub errors: Vec<EventError>,
+    pub results: Vec<Result<IndexResult, String>>,
+}
+
+/// Open offer on a token
+///
+/// * `time` - time the offer was made at. Older cancels and offers from the buyer are ignored.
 #[derive(CandidType, Clone, Deserialize, Debug)]
 pub struct Offer {
     pub buyer: Principal,
     pub fungible: Principal,
     pub price: Nat,
+    pub time: Nat,
 }
 #[derive(CandidType, Clone, Deserialize, Debug)]
 pub struct Sale {
@@ -90,15 +558,76 @@ pub struct Sale {
 pub struct TokenData {
     pub id: String,
     pub traits: Option<HashMap<String, GenericValue>>,
+    pub owner: Option<Principal>,
+    pub last_transfer: Option<Nat>,
 
     pub offers: Vec<Offer>,
     pub best_offer: Option<Nat>,
     pub price: Option<Nat>,
+    pub listing_fungible: Option<Principal>,
+    pub seller: Option<Principal>,
     pub last_sale: Option<Sale>,
 
     pub last_listing: Option<Nat>,
     pub last_offer: Option<Nat>,
 }
 
-pub type TokenIndex = HashMap<String, Vec<String>>;
 pub type GenericIndex = HashMap<GenericValue, Vec<String>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::parser::value::IDLArgs;
+
+    /// decode an operation from its candid text form, as sent by clients
+    fn decode(text: &str) -> Operation {
+        let bytes = text.parse::<IDLArgs>().unwrap().to_bytes().unwrap();
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    #[test]
+    fn operation_variant_names() {
+        let buyer = Principal::anonymous();
+
+        assert!(matches!(
+            decode("(variant { mint = record { traits = null; to = null } })"),
+            Operation::Mint {
+                traits: None,
+                to: None
+            }
+        ));
+        assert!(matches!(
+            decode(r#"(variant { transfer = record { to = principal "2vxsx-fae" } })"#),
+            Operation::Transfer { to } if to == buyer
+        ));
+        assert!(matches!(decode("(variant { burn })"), Operation::Burn));
+        assert!(matches!(
+            decode("(variant { updateMetadata = record { traits = null } })"),
+            Operation::UpdateMetadata { traits: None }
+        ));
+        assert!(matches!(
+            decode("(variant { makeListing = record { price = 10 : nat } })"),
+            Operation::MakeListing { price } if price == 10u64
+        ));
+        assert!(matches!(
+            decode("(variant { cancelListing })"),
+            Operation::CancelListing
+        ));
+        assert!(matches!(
+            decode(r#"(variant { makeOffer = record { buyer = principal "2vxsx-fae"; price = 5 : nat } })"#),
+            Operation::MakeOffer { buyer: b, price } if b == buyer && price == 5u64
+        ));
+        assert!(matches!(
+            decode(r#"(variant { cancelOffer = record { buyer = principal "2vxsx-fae" } })"#),
+            Operation::CancelOffer { buyer: b } if b == buyer
+        ));
+        assert!(matches!(
+            decode(r#"(variant { directBuy = record { buyer = principal "2vxsx-fae"; price = 7 : nat } })"#),
+            Operation::DirectBuy { buyer: b, price } if b == buyer && price == 7u64
+        ));
+        assert!(matches!(
+            decode(r#"(variant { acceptOffer = record { buyer = principal "2vxsx-fae"; price = 7 : nat } })"#),
+            Operation::AcceptOffer { buyer: b, price } if b == buyer && price == 7u64
+        ));
+    }
+}
@@ -41,38 +41,41 @@ for i in {0..99}; do
     record {
       nft_canister_id=principal\"$nft_canister_id\";
       token_id=\"$i\";
-      operation=\"mint\";
-      traits=opt vec {
-        record {
-          \"smallgem\";
-          variant {
-            \"TextContent\" = $trait1
-          }
-        };
-        record {
-          \"biggem\";
-          variant {
-            \"TextContent\" = $trait2
-          }
-        };
-        record {
-          \"base\";
-          variant {
-            \"TextContent\" = $trait3
-          }
-        };
-        record {
-          \"rim\";
-          variant {
-            \"TextContent\" = $trait4
-          }
-        };
-        record {
-          \"location\";
-          variant {
-            \"TextContent\" = $trait5
-          }
-        };
+      operation=variant {
+        mint=record {
+          traits=opt vec {
+            record {
+              \"smallgem\";
+              variant {
+                \"TextContent\" = $trait1
+              }
+            };
+            record {
+              \"biggem\";
+              variant {
+                \"TextContent\" = $trait2
+              }
+            };
+            record {
+              \"base\";
+              variant {
+                \"TextContent\" = $trait3
+              }
+            };
+            record {
+              \"rim\";
+              variant {
+                \"TextContent\" = $trait4
+              }
+            };
+            record {
+              \"location\";
+              variant {
+                \"TextContent\" = $trait5
+              }
+            };
+          };
+        }
       };
     }
   )"
@@ -137,8 +140,11 @@ for i in {0..4}; do
     record {
       nft_canister_id=principal\"$nft_canister_id\";
       token_id=\"$i\";
-      operation=\"makeListing\";
-      price=opt($price);
+      operation=variant {
+        makeListing=record {
+          price=$price;
+        }
+      };
     }
   )"
 done
@@ -167,9 +173,12 @@ for i in {5..9}; do
     record {
       nft_canister_id=principal\"$nft_canister_id\";
       token_id=\"$i\";
-      operation=\"makeOffer\";
-      buyer=opt principal\"$user_a\";
-      price=opt($price);
+      operation=variant {
+        makeOffer=record {
+          buyer=principal\"$user_a\";
+          price=$price;
+        }
+      };
     }
   )"
 done
@@ -194,8 +203,11 @@ dfx canister --network $NETWORK call curation insert "(
   record {
     nft_canister_id=principal\"$nft_canister_id\";
     token_id=\"5\";
-    operation=\"cancelOffer\";
-    buyer=opt principal\"$user_a\";
+    operation=variant {
+      cancelOffer=record {
+        buyer=principal\"$user_a\";
+      }
+    };
   }
 )"
 
@@ -219,9 +231,12 @@ dfx canister --network $NETWORK call curation insert "(
   record {
     nft_canister_id=principal\"$nft_canister_id\";
     token_id=\"6\";
-    operation=\"makeOffer\";
-    buyer=opt principal\"$user_b\";
-    price=opt(200);
+    operation=variant {
+      makeOffer=record {
+        buyer=principal\"$user_b\";
+        price=200;
+      }
+    };
   }
 )"
 
@@ -246,9 +261,12 @@ dfx canister --network $NETWORK call curation insert "(
   record {
     nft_canister_id=principal\"$nft_canister_id\";
     token_id=\"4\";
-    operation=\"directBuy\";
-    buyer=opt principal\"$user_a\";
-    price=opt($price);
+    operation=variant {
+      directBuy=record {
+        buyer=principal\"$user_a\";
+        price=$price;
+      }
+    };
   }
 )"
 
@@ -257,9 +275,12 @@ dfx canister --network $NETWORK call curation insert "(
   record {
     nft_canister_id=principal\"$nft_canister_id\";
     token_id=\"5\";
-    operation=\"acceptOffer\";
-    buyer=opt principal\"$user_b\";
-    price=opt(200);
+    operation=variant {
+      acceptOffer=record {
+        buyer=principal\"$user_b\";
+        price=200;
+      }
+    };
   }
 )"
 